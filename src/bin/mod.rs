@@ -1,20 +1,63 @@
 use std::{
+    collections::HashMap,
     fs,
     io::{self, Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::Duration,
 };
 
 use clap::{Parser, Subcommand};
 use lepatch::{
-    command::{backup, restore},
-    reader::ChunkerConfig,
-    storage,
+    command::{
+        BackupOptions, RestoreOptions, backup, backup_list, backup_stream, recover, replace_file,
+        restore,
+    },
+    config::RepositoryConfig,
+    crypto::{EncryptionConfig, MasterKey, RepositoryKey},
+    location::Location,
+    lock::RepositoryLock,
+    progress::Progress,
+    storage::{self, Storage, StorageGet, StoragePut, StorageUrl},
+};
+use serde::Serialize;
+use tokio::{
+    process::{ChildStdout, Command},
+    task::JoinHandle,
+    time::Instant,
 };
+use tokio_util::sync::CancellationToken;
 use tracing::level_filters::LevelFilter;
 use walkdir::WalkDir;
+use zeroize::Zeroizing;
+
+use crate::progress::ProgressMode;
+
+mod progress;
+
+/// Prints a message for the user on stdout, or on stderr when stdout carries
+/// JSON progress events, so it stays one JSON object per line.
+macro_rules! say {
+    ($mode:expr, $($arg:tt)*) => {
+        if matches!($mode, ProgressMode::Json) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
 
 const INDEX_EXTENSION: &str = "idx";
+/// Index files number versions with three digits.
+const MAX_VERSION: u16 = 999;
 const BLOB_EXTENSION: &str = "bin";
+const CHECKPOINT_EXTENSION: &str = "checkpoint";
+const CHUNK_MAP_EXTENSION: &str = "chunks";
+const CONFIG_EXTENSION: &str = "json";
+const PASSWORD_ENV: &str = "LEPATCH_PASSWORD";
+const NEW_PASSWORD_ENV: &str = "LEPATCH_NEW_PASSWORD";
+const CHECKPOINT_INTERVAL: u64 = 1024 * 1024 * 1024;
 
 #[derive(Debug, Clone, Parser)]
 struct Args {
@@ -22,6 +65,20 @@ struct Args {
     command: Commands,
     #[arg(long, default_value_t = false)]
     verbose: bool,
+    #[arg(long, value_enum, default_value_t = ProgressMode::default())]
+    progress: ProgressMode,
+    /// Stop after this many seconds, keeping a checkpoint for backups.
+    #[arg(long)]
+    timeout: Option<u64>,
+    /// Storage of the repository, e.g. `dir:///mnt/backup` or
+    /// `s3://bucket/prefix`. Defaults to `file://<name>.bin`; index and
+    /// configuration files always stay in the working directory.
+    #[arg(long, global = true)]
+    repo: Option<StorageUrl>,
+    /// File holding the password of an encrypted repository. Defaults to the
+    /// `LEPATCH_PASSWORD` environment variable.
+    #[arg(long, global = true)]
+    password_file: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -29,12 +86,117 @@ enum Commands {
     Backup {
         source: PathBuf,
         name: String,
+        /// Back up only the paths listed in this file (`-` for stdin),
+        /// relative to `source`, instead of walking it.
+        #[arg(long)]
+        files_from: Option<PathBuf>,
+        /// Paths in `--files-from` are separated by NUL instead of newline.
+        #[arg(long, short = '0', default_value_t = false)]
+        null: bool,
+        /// Chunk and hash everything without writing to the repository, then
+        /// print the estimated size and deduplication ratio.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
+    },
+    /// Back up the standard input, or the output of `command`, as a single
+    /// file named `path` inside the snapshot.
+    BackupStream {
+        name: String,
+        path: PathBuf,
+        #[arg(last = true)]
+        command: Vec<String>,
+        /// Chunk and hash the stream without writing to the repository, then
+        /// print the estimated size and deduplication ratio.
+        #[arg(long, default_value_t = false)]
+        dry_run: bool,
     },
     Restore {
         destination: PathBuf,
         name: String,
         version: Option<u16>,
     },
+    /// Encrypt the new repository `name` with a key protected by the
+    /// password.
+    Init {
+        name: String,
+        /// Describes the first key, e.g. who it belongs to.
+        #[arg(long)]
+        label: Option<String>,
+        /// Seal objects to a public key kept in the config, so backups need
+        /// no password and cannot read earlier snapshots. Restoring still
+        /// needs it.
+        #[arg(long)]
+        write_only: bool,
+    },
+    /// Manage the passwords of an encrypted repository.
+    Key {
+        #[command(subcommand)]
+        command: KeyCommands,
+    },
+    /// Rebuild the index files of `name` from the snapshots found in its blob
+    /// file, adding those no index file refers to.
+    Recover { name: String },
+    /// Remove the locks of `name` left behind by processes that no longer
+    /// run.
+    Unlock {
+        name: String,
+        /// Also remove the locks of running processes and of other hosts.
+        #[arg(long, default_value_t = false)]
+        all: bool,
+    },
+    /// Serve the storage at `url` on stdin and stdout, for clients using a
+    /// `lepatch+ssh://` repository. The repository stays locked until the
+    /// client disconnects.
+    Serve {
+        url: StorageUrl,
+        /// Take a shared lock, for clients that only read.
+        #[arg(long, default_value_t = false)]
+        shared: bool,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum KeyCommands {
+    /// List the keys able to open `name`.
+    List { name: String },
+    /// Add a key opened by a new password, read from `--new-password-file` or
+    /// `LEPATCH_NEW_PASSWORD`.
+    Add {
+        name: String,
+        #[arg(long)]
+        label: Option<String>,
+        #[arg(long)]
+        new_password_file: Option<PathBuf>,
+    },
+    /// Remove the key with id `id`. The key of the password in use cannot be
+    /// removed. This only stops the password from opening the config: the
+    /// master key does not change, so anyone who opened the repository with
+    /// it before, or kept a copy of the config, can still read every
+    /// snapshot.
+    Remove { name: String, id: String },
+    /// Change the password of the key in use to a new one, read from
+    /// `--new-password-file` or `LEPATCH_NEW_PASSWORD`.
+    Passwd {
+        name: String,
+        #[arg(long)]
+        new_password_file: Option<PathBuf>,
+    },
+    /// Write the config backup hosts of the write-only repository `name` need
+    /// to `output`. It holds no key, so backups run without a password and
+    /// cannot read any snapshot; copy it next to the repository there.
+    Export { name: String, output: PathBuf },
+}
+
+impl KeyCommands {
+    fn name(&self) -> &str {
+        match self {
+            Self::List { name }
+            | Self::Add { name, .. }
+            | Self::Remove { name, .. }
+            | Self::Passwd { name, .. }
+            | Self::Export { name, .. } => name,
+        }
+    }
 }
 
 #[tokio::main]
@@ -47,34 +209,144 @@ async fn main() -> io::Result<()> {
         LevelFilter::INFO
     };
 
-    tracing_subscriber::fmt().with_max_level(log_level).init();
+    tracing_subscriber::fmt()
+        .with_max_level(log_level)
+        .with_writer(io::stderr)
+        .init();
+
+    // Held until the command finishes.
+    let _lock = match &args.command {
+        Commands::Backup { name, dry_run, .. } | Commands::BackupStream { name, dry_run, .. } => {
+            lock_repository(&repo_url(args.repo.clone(), name), !dry_run).await?
+        }
+        Commands::Restore { name, .. } => {
+            lock_repository(&repo_url(args.repo.clone(), name), false).await?
+        }
+        Commands::Init { name, .. } | Commands::Recover { name } => {
+            lock_repository(&repo_url(args.repo.clone(), name), true).await?
+        }
+        Commands::Key { command } => {
+            let url = repo_url(args.repo.clone(), command.name());
+            let exclusive = !matches!(
+                command,
+                KeyCommands::List { .. } | KeyCommands::Export { .. }
+            );
+            lock_repository(&url, exclusive).await?
+        }
+        Commands::Serve { url, shared } => lock_repository(url, !shared).await?,
+        Commands::Unlock { .. } => None,
+    };
+
+    let mode = args.progress;
+    let (progress, progress_handle) = progress::spawn(mode);
+
+    // The first Ctrl-C stops at the next chunk boundary after a checkpoint;
+    // a second one exits right away, e.g. when blocked on stdin or storage.
+    let cancel = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if cancel.is_cancelled() {
+                    std::process::exit(130);
+                }
+                cancel.cancel();
+            }
+        }
+    });
+
+    let deadline = args
+        .timeout
+        .map(|v| Instant::now() + Duration::from_secs(v));
 
     match args.command {
-        Commands::Backup { source, name } => {
-            let last_version = get_last_version(&name).unwrap_or(1);
-            let index_extension = format!("{:03}.{}", last_version + 1, INDEX_EXTENSION);
-            let index_path = PathBuf::from(&name).with_extension(index_extension);
-
-            let mut index_file = {
-                fs::OpenOptions::new()
-                    .write(true)
-                    .create_new(true)
-                    .open(index_path)?
+        Commands::Backup {
+            source,
+            name,
+            files_from,
+            null,
+            dry_run,
+        } => {
+            let source = match files_from {
+                Some(list_path) => {
+                    let separator = if null { b'\0' } else { b'\n' };
+                    BackupSource::List(source, read_path_list(&list_path, separator)?)
+                }
+                None => BackupSource::Walk(source),
             };
 
-            let config = ChunkerConfig {
-                min_size: 8 * 1024,
-                avg_size: 16 * 1024,
-                max_size: 64 * 1024,
+            let options = BackupOptions {
+                progress: Some(progress),
+                cancel,
+                deadline,
+                ..Default::default()
             };
 
-            let storage_path = PathBuf::from(&name).with_extension(BLOB_EXTENSION);
-            let storage = storage::BlobFileStorage::new(storage_path).await?;
+            let url = repo_url(args.repo, &name);
+            let password_file = args.password_file.as_deref();
+            backup_to(&name, &url, password_file, source, dry_run, options).await?;
+            finish_backup(&name, dry_run, mode, progress_handle).await?;
+        }
+        Commands::BackupStream {
+            name,
+            path,
+            command,
+            dry_run,
+        } => {
+            let options = BackupOptions {
+                progress: Some(progress),
+                cancel,
+                deadline,
+                ..Default::default()
+            };
 
-            let key = backup(source, None, storage, config).await?;
+            let url = repo_url(args.repo, &name);
+            let password_file = args.password_file.as_deref();
+
+            match command.split_first() {
+                Some((program, args)) => {
+                    let mut child = Command::new(program)
+                        .args(args)
+                        .stdout(Stdio::piped())
+                        .kill_on_drop(true)
+                        .spawn()?;
+                    let stdout = child
+                        .stdout
+                        .take()
+                        .ok_or_else(|| io::Error::other("child stdout is not captured"))?;
+
+                    let source = BackupSource::Stream(path, child_output(stdout)?);
+                    let key = match run_backup(&name, &url, password_file, source, dry_run, options)
+                        .await
+                    {
+                        Ok(v) => v,
+                        Err(e) => {
+                            if let Err(kill_error) = child.kill().await {
+                                tracing::warn!("failed to kill {}: {}", program, kill_error);
+                            }
+                            return Err(e);
+                        }
+                    };
+
+                    let status = child.wait().await?;
+                    if !status.success() {
+                        return Err(io::Error::other(format!(
+                            "command {} exited with {}",
+                            program, status
+                        )));
+                    }
+
+                    if let Some(key) = key {
+                        write_index(&name, &key)?;
+                    }
+                }
+                None => {
+                    let source = BackupSource::Stream(path, Box::new(io::stdin()));
+                    backup_to(&name, &url, password_file, source, dry_run, options).await?;
+                }
+            }
 
-            index_file.write_all(key.as_bytes())?;
-            index_file.flush()?;
+            finish_backup(&name, dry_run, mode, progress_handle).await?;
         }
         Commands::Restore {
             destination,
@@ -86,23 +358,591 @@ async fn main() -> io::Result<()> {
                 None => get_last_version(&name).unwrap_or(1),
             };
 
-            let index_extension = format!("{:03}.{}", version, INDEX_EXTENSION);
-            let index_path = PathBuf::from(&name).with_extension(index_extension);
-            let mut index_file = fs::File::open(index_path)?;
+            let key = read_index(&name, version)?;
+
+            let config = read_config(&name)?;
+            let url = repo_url(args.repo, &name);
+            let repository_key = unlock(&config, args.password_file.as_deref(), false)?;
+            let hash_key = repository_key.as_ref().map(RepositoryKey::chunk_id_key);
+            let storage = open_storage_read(&url, &config, repository_key).await?;
+
+            let options = RestoreOptions {
+                progress: Some(progress),
+                cancel,
+                deadline,
+                hash_key,
+            };
+
+            restore(destination, key, storage, options).await?;
+            progress_handle.await.map_err(io::Error::other)?;
+        }
+        Commands::Recover { name } => {
+            let StorageUrl::File(path) = repo_url(args.repo, &name) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "only blob file repositories can be recovered",
+                ));
+            };
+
+            let config = read_config(&name)?;
+            let blob = Arc::new(storage::BlobFileStorage::<false>::new(path).await?);
+            let storage: Box<dyn StorageGet> =
+                match unlock(&config, args.password_file.as_deref(), false)? {
+                    Some(key) => Box::new(storage::EncryptedStorage::new(blob.clone(), key)),
+                    None => Box::new(blob.clone()),
+                };
+
+            let mut known = HashMap::new();
+            for version in 1..=get_last_version(&name).unwrap_or(0) {
+                match read_index(&name, version) {
+                    Ok(key) => {
+                        known.insert(key, version);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let recovery = recover(&blob, &storage).await?;
+            let recovered = restore_index(&name, &recovery.snapshots, &known)?;
+
+            say!(mode, "recovered {} snapshots", recovered);
+            if recovery.skipped > 0 {
+                say!(mode, "skipped {} unreadable snapshots", recovery.skipped);
+            }
+        }
+        Commands::Init {
+            name,
+            label,
+            write_only,
+        } => {
+            let mut config = read_config(&name)?;
+            if config.encryption.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("repository {} is already encrypted", name),
+                ));
+            }
+            if get_last_version(&name).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("repository {} already has snapshots", name),
+                ));
+            }
+
+            let password = read_password(args.password_file.as_deref(), PASSWORD_ENV)?;
+            let encryption =
+                EncryptionConfig::new(&MasterKey::generate(), &password, label, write_only)?;
+            say!(mode, "created key {}", encryption.slots[0].id);
+            if let Some(public_key) = &encryption.public_key {
+                say!(mode, "sealing objects to public key {}", public_key);
+                say!(
+                    mode,
+                    "run `key export` for the config of hosts backing up without a password"
+                );
+            }
+
+            config.encryption = Some(encryption);
+            write_config(&name, &config)?;
+        }
+        Commands::Key { command } => {
+            let name = command.name().to_string();
+            let mut config = read_config(&name)?;
+            let encryption = config.encryption.as_mut().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("repository {} is not encrypted", name),
+                )
+            })?;
+
+            let password = || read_password(args.password_file.as_deref(), PASSWORD_ENV);
 
-            let mut key = String::new();
-            index_file.read_to_string(&mut key)?;
+            match command {
+                KeyCommands::List { .. } => {
+                    for slot in encryption.slots.iter() {
+                        say!(
+                            mode,
+                            "{}  created {}  {}",
+                            slot.id,
+                            slot.created,
+                            slot.label.as_deref().unwrap_or("")
+                        );
+                    }
 
-            let storage_path = PathBuf::from(&name).with_extension(BLOB_EXTENSION);
-            let storage = storage::BlobFileStorage::<false>::new(storage_path).await?;
+                    return Ok(());
+                }
+                KeyCommands::Add {
+                    label,
+                    new_password_file,
+                    ..
+                } => {
+                    let key = encryption.unlock(&password()?)?;
+                    let new_password =
+                        read_password(new_password_file.as_deref(), NEW_PASSWORD_ENV)?;
+                    let slot = encryption.add(&key, &new_password, label)?;
+                    say!(mode, "added key {}", slot.id);
+                }
+                KeyCommands::Remove { id, .. } => {
+                    let (index, _) = encryption.unlock_slot(&password()?)?;
+                    if encryption.position(&id)? == index {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("key {} is the one in use", id),
+                        ));
+                    }
+                    encryption.remove(&id)?;
+                    say!(mode, "removed key {}", id);
+                }
+                KeyCommands::Passwd {
+                    new_password_file, ..
+                } => {
+                    let password = password()?;
+                    let new_password =
+                        read_password(new_password_file.as_deref(), NEW_PASSWORD_ENV)?;
+                    let slot = encryption.change_password(&password, &new_password)?;
+                    say!(mode, "changed password of key {}", slot.id);
+                }
+                KeyCommands::Export { output, .. } => {
+                    let key = encryption.unlock(&password()?)?;
+                    let exported = RepositoryConfig {
+                        encryption: Some(encryption.export(&key)?),
+                        ..config.clone()
+                    };
+                    write_config_file(&output, &exported)?;
+                    say!(mode, "exported the backup config to {}", output.display());
 
-            restore(destination, key, storage).await?;
+                    return Ok(());
+                }
+            }
+
+            write_config(&name, &config)?;
+        }
+        Commands::Unlock { name, all } => {
+            let url = repo_url(args.repo, &name);
+            let locks = url.locks().await?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("locks of {} are kept on its host, unlock it there", url),
+                )
+            })?;
+
+            let removed = RepositoryLock::unlock(&*locks, all).await?;
+            for info in removed.iter() {
+                say!(mode, "removed {}", info);
+            }
+        }
+        Commands::Serve { url, .. } => {
+            let storage = url.open(storage::SyncPolicy::default()).await?;
+            storage::serve(&*storage, tokio::io::stdin(), tokio::io::stdout()).await?;
         }
     }
 
     Ok(())
 }
 
+fn repo_url(repo: Option<StorageUrl>, name: &str) -> StorageUrl {
+    repo.unwrap_or_else(|| StorageUrl::File(PathBuf::from(name).with_extension(BLOB_EXTENSION)))
+}
+
+fn index_path(name: &str, version: u16) -> PathBuf {
+    let index_extension = format!("{:03}.{}", version, INDEX_EXTENSION);
+    PathBuf::from(name).with_extension(index_extension)
+}
+
+fn checkpoint_path(name: &str) -> PathBuf {
+    PathBuf::from(name).with_extension(CHECKPOINT_EXTENSION)
+}
+
+fn chunk_map_path(name: &str) -> PathBuf {
+    PathBuf::from(name).with_extension(CHUNK_MAP_EXTENSION)
+}
+
+/// Locks the repository at `url` against other processes, exclusively for
+/// commands that write to it. Repositories reached through ssh are locked by
+/// the remote `serve` command instead.
+async fn lock_repository(url: &StorageUrl, exclusive: bool) -> io::Result<Option<RepositoryLock>> {
+    let Some(locks) = url.locks().await? else {
+        return Ok(None);
+    };
+
+    let lock = if exclusive {
+        RepositoryLock::exclusive(locks).await?
+    } else {
+        RepositoryLock::shared(locks).await?
+    };
+
+    Ok(Some(lock))
+}
+
+/// Reads the settings of repository `name`, using the defaults when it has
+/// no configuration file.
+fn read_config(name: &str) -> io::Result<RepositoryConfig> {
+    let path = PathBuf::from(name).with_extension(CONFIG_EXTENSION);
+
+    match fs::read(path) {
+        Ok(buffer) => serde_json::from_slice(&buffer)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(RepositoryConfig::default()),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_config(name: &str, config: &RepositoryConfig) -> io::Result<()> {
+    write_config_file(
+        &PathBuf::from(name).with_extension(CONFIG_EXTENSION),
+        config,
+    )
+}
+
+fn write_config_file(path: &Path, config: &RepositoryConfig) -> io::Result<()> {
+    let buffer = serde_json::to_vec_pretty(config).map_err(io::Error::other)?;
+    replace_file(path, &buffer)
+}
+
+/// Reads a password from `password_file`, without its trailing line ending,
+/// or from the environment variable `env`. It is wiped from memory when
+/// dropped.
+fn read_password(password_file: Option<&Path>, env: &str) -> io::Result<Zeroizing<Vec<u8>>> {
+    match password_file {
+        Some(path) => {
+            let mut password = Zeroizing::new(fs::read(path)?);
+            if password.last() == Some(&b'\n') {
+                password.pop();
+                if password.last() == Some(&b'\r') {
+                    password.pop();
+                }
+            }
+
+            Ok(password)
+        }
+        None => std::env::var_os(env)
+            .map(|v| Zeroizing::new(v.into_encoded_bytes()))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("set {} or pass a password file", env),
+                )
+            }),
+    }
+}
+
+/// Unwraps the master key of an encrypted repository. Backups of write-only
+/// repositories, when `sealing` is set, run without a password unless one is
+/// given.
+fn unlock(
+    config: &RepositoryConfig,
+    password_file: Option<&Path>,
+    sealing: bool,
+) -> io::Result<Option<RepositoryKey>> {
+    let Some(encryption) = &config.encryption else {
+        return Ok(None);
+    };
+
+    let password_given = password_file.is_some() || std::env::var_os(PASSWORD_ENV).is_some();
+    let master_key = if sealing && encryption.public_key.is_some() && !password_given {
+        None
+    } else {
+        let password = read_password(password_file, PASSWORD_ENV)?;
+        Some(encryption.unlock(&password)?)
+    };
+
+    encryption.repository_key(master_key).map(Some)
+}
+
+/// Opens the storage of a repository with retries and, if the repository is
+/// encrypted, encryption with `repository_key`.
+async fn open_storage(
+    url: &StorageUrl,
+    config: &RepositoryConfig,
+    repository_key: Option<RepositoryKey>,
+) -> io::Result<Box<dyn Storage>> {
+    let storage = url.open(config.sync).await?;
+    let storage = storage::RetryStorage::new(storage, config.retry.clone());
+
+    Ok(match repository_key {
+        Some(key) => Box::new(storage::EncryptedStorage::new(storage, key)),
+        None => Box::new(storage),
+    })
+}
+
+async fn open_storage_read(
+    url: &StorageUrl,
+    config: &RepositoryConfig,
+    repository_key: Option<RepositoryKey>,
+) -> io::Result<Box<dyn StorageGet>> {
+    let storage = url.open_read().await?;
+    let storage = storage::RetryStorage::new(storage, config.retry.clone());
+
+    Ok(match repository_key {
+        Some(key) => Box::new(storage::EncryptedStorage::new(storage, key)),
+        None => Box::new(storage),
+    })
+}
+
+fn read_index(name: &str, version: u16) -> io::Result<Location> {
+    let mut index_file = fs::File::open(index_path(name, version))?;
+
+    let mut key = String::new();
+    index_file.read_to_string(&mut key)?;
+
+    key.parse()
+}
+
+fn write_index(name: &str, key: &Location) -> io::Result<()> {
+    let version = get_last_version(name).unwrap_or(1) + 1;
+    write_index_version(name, version, key)
+}
+
+fn write_index_version(name: &str, version: u16, key: &Location) -> io::Result<()> {
+    let mut index_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(index_path(name, version))?;
+
+    index_file.write_all(key.to_string().as_bytes())?;
+    index_file.sync_all()
+}
+
+/// Writes index files for the `snapshots` found in the blob, in write order,
+/// that no index file names yet. Each gets the first free version after the
+/// snapshots written before it, so the latest version stays the newest
+/// snapshot. A snapshot is skipped with a warning when the versions up to the
+/// next indexed snapshot are all taken. Returns how many were indexed.
+fn restore_index(
+    name: &str,
+    snapshots: &[Location],
+    known: &HashMap<Location, u16>,
+) -> io::Result<usize> {
+    let mut recovered = 0;
+    let mut previous = 0;
+
+    for (position, key) in snapshots.iter().enumerate() {
+        if let Some(version) = known.get(key) {
+            previous = previous.max(*version);
+            continue;
+        }
+
+        let next = snapshots[position + 1..]
+            .iter()
+            .filter_map(|v| known.get(v))
+            .min()
+            .copied()
+            .unwrap_or(MAX_VERSION + 1);
+
+        let version = (previous + 1..next).find(|v| !index_path(name, *v).exists());
+        let Some(version) = version else {
+            tracing::warn!(
+                "no free version between {} and {} for snapshot {}",
+                previous,
+                next,
+                key
+            );
+            continue;
+        };
+
+        write_index_version(name, version, key)?;
+        previous = version;
+        recovered += 1;
+    }
+
+    Ok(recovered)
+}
+
+enum BackupSource {
+    Walk(PathBuf),
+    List(PathBuf, Vec<PathBuf>),
+    Stream(PathBuf, Box<dyn Read + Send>),
+}
+
+/// Backs up `source` on top of the latest snapshot of `name` and records the
+/// new snapshot in the next index file, unless this is a dry run.
+async fn backup_to(
+    name: &str,
+    url: &StorageUrl,
+    password_file: Option<&Path>,
+    source: BackupSource,
+    dry_run: bool,
+    options: BackupOptions,
+) -> io::Result<()> {
+    if let Some(key) = run_backup(name, url, password_file, source, dry_run, options).await? {
+        write_index(name, &key)?;
+    }
+
+    Ok(())
+}
+
+async fn run_backup(
+    name: &str,
+    url: &StorageUrl,
+    password_file: Option<&Path>,
+    source: BackupSource,
+    dry_run: bool,
+    options: BackupOptions,
+) -> io::Result<Option<Location>> {
+    let config = read_config(name)?;
+    let repository_key = unlock(&config, password_file, true)?;
+
+    // Without the password, earlier snapshots of a write-only repository
+    // cannot be read, so chunks are reused through the local chunk map.
+    let can_read = repository_key.as_ref().is_none_or(RepositoryKey::can_open);
+    if !can_read {
+        tracing::info!("backing up write-only, reusing the chunks of the local chunk map");
+    }
+
+    let base_key = match get_last_version(name) {
+        Some(version) if can_read => Some(read_index(name, version)?),
+        _ => None,
+    };
+
+    let resume_key = match fs::read_to_string(checkpoint_path(name)) {
+        Ok(key) if !dry_run && can_read => Some(key.parse()?),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => None,
+    };
+
+    let options = BackupOptions {
+        base_key,
+        checkpoint_interval: (!dry_run).then_some(CHECKPOINT_INTERVAL),
+        checkpoint_file: (!dry_run).then(|| checkpoint_path(name)),
+        resume_key,
+        hash_key: repository_key.as_ref().map(RepositoryKey::chunk_id_key),
+        chunk_map_file: (!can_read).then(|| chunk_map_path(name)),
+        chunk_map_read_only: dry_run,
+        ..options
+    };
+
+    if dry_run {
+        let storage = open_storage_read(url, &config, repository_key).await?;
+        let storage = storage::DryRunStorage::new(storage);
+        backup_source(source, storage, options).await?;
+
+        return Ok(None);
+    }
+
+    let storage = open_storage(url, &config, repository_key).await?;
+    let key = backup_source(source, storage, options).await?;
+
+    Ok(Some(key))
+}
+
+async fn backup_source<S: StorageGet + StoragePut>(
+    source: BackupSource,
+    storage: S,
+    options: BackupOptions,
+) -> io::Result<Location> {
+    match source {
+        BackupSource::Walk(root) => backup(root, storage, options).await,
+        BackupSource::List(root, paths) => backup_list(root, paths, storage, options).await,
+        BackupSource::Stream(path, reader) => backup_stream(path, reader, storage, options).await,
+    }
+}
+
+/// Dry-run estimate printed as the last JSON event.
+#[derive(Serialize)]
+struct Estimate {
+    event: &'static str,
+    logical_bytes: u64,
+    new_bytes: u64,
+    chunks_new: u64,
+    chunks_reused: u64,
+    dedup_ratio: Option<f64>,
+}
+
+/// Waits for the progress task, then drops the checkpoint of the completed
+/// backup or prints the estimate of a dry run, as a last `estimate` event in
+/// JSON mode.
+async fn finish_backup(
+    name: &str,
+    dry_run: bool,
+    mode: ProgressMode,
+    progress_handle: JoinHandle<Progress>,
+) -> io::Result<()> {
+    let totals = progress_handle.await.map_err(io::Error::other)?;
+
+    if !dry_run {
+        match fs::remove_file(checkpoint_path(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+
+        return Ok(());
+    }
+
+    // Nothing new means every chunk is already stored, so there is no
+    // meaningful ratio to print.
+    let ratio =
+        (totals.bytes_new != 0).then(|| totals.bytes_chunked as f64 / totals.bytes_new as f64);
+
+    if matches!(mode, ProgressMode::Json) {
+        let estimate = Estimate {
+            event: "estimate",
+            logical_bytes: totals.bytes_chunked,
+            new_bytes: totals.bytes_new,
+            chunks_new: totals.chunks_new,
+            chunks_reused: totals.chunks_reused,
+            dedup_ratio: ratio,
+        };
+        let line = serde_json::to_string(&estimate).map_err(io::Error::other)?;
+        println!("{}", line);
+
+        return Ok(());
+    }
+
+    println!("logical size:     {} bytes", totals.bytes_chunked);
+    println!("unique new bytes: {} bytes", totals.bytes_new);
+    println!(
+        "chunks:           {} ({} new, {} reused)",
+        totals.chunks_new + totals.chunks_reused,
+        totals.chunks_new,
+        totals.chunks_reused
+    );
+    match ratio {
+        Some(ratio) => println!("dedup ratio:      {:.2}", ratio),
+        None => println!("dedup ratio:      n/a (no new data)"),
+    }
+
+    Ok(())
+}
+
+fn read_path_list(list_path: &Path, separator: u8) -> io::Result<Vec<PathBuf>> {
+    let mut buffer = Vec::new();
+    if list_path == Path::new("-") {
+        io::stdin().read_to_end(&mut buffer)?;
+    } else {
+        fs::File::open(list_path)?.read_to_end(&mut buffer)?;
+    }
+
+    buffer
+        .split(|v| *v == separator)
+        .filter(|v| !v.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+/// Hands the piped output of a child to the chunker, which reads it blocking.
+#[cfg(unix)]
+fn child_output(stdout: ChildStdout) -> io::Result<Box<dyn Read + Send>> {
+    Ok(Box::new(fs::File::from(stdout.into_owned_fd()?)))
+}
+
+#[cfg(windows)]
+fn child_output(stdout: ChildStdout) -> io::Result<Box<dyn Read + Send>> {
+    Ok(Box::new(fs::File::from(stdout.into_owned_handle()?)))
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> io::Result<PathBuf> {
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+    Ok(PathBuf::from(OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> io::Result<PathBuf> {
+    let path =
+        std::str::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(PathBuf::from(path))
+}
+
 fn get_last_version(name: &str) -> Option<u16> {
     WalkDir::new(".")
         .max_depth(1)
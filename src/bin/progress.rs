@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use lepatch::progress::{self, Progress, ProgressEvent, ProgressReceiver, ProgressSender};
+use tokio::task::JoinHandle;
+
+#[derive(Debug, Clone, Copy, Default, ValueEnum)]
+pub enum ProgressMode {
+    /// Human readable progress bar on stderr.
+    #[default]
+    Bar,
+    /// One JSON object per event on stdout; other messages go to stderr.
+    Json,
+    None,
+}
+
+/// Spawns a task rendering progress events; it resolves to the final totals
+/// once every sender has been dropped.
+pub fn spawn(mode: ProgressMode) -> (ProgressSender, JoinHandle<Progress>) {
+    let (sender, receiver) = progress::channel();
+
+    let handle = tokio::spawn(render(mode, receiver));
+
+    (sender, handle)
+}
+
+async fn render(mode: ProgressMode, mut receiver: ProgressReceiver) -> Progress {
+    let mut totals = Progress::default();
+
+    let bar = match mode {
+        ProgressMode::Bar => {
+            let bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
+            bar.set_style(
+                ProgressStyle::with_template(
+                    "{spinner} [{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, {eta}) {msg}",
+                )
+                .expect("progress template is valid"),
+            );
+            bar.enable_steady_tick(Duration::from_millis(200));
+            Some(bar)
+        }
+        _ => None,
+    };
+
+    while let Some(event) = receiver.recv().await {
+        totals.update(&event);
+
+        // Printed in every mode, the chunks it listed are stored again.
+        if let ProgressEvent::ChunkMapDropped { path, reason } = &event {
+            let message = format!(
+                "warning: dropped unreadable chunk map {}: {}",
+                path.display(),
+                reason
+            );
+            match &bar {
+                Some(bar) => bar.suspend(|| eprintln!("{}", message)),
+                None => eprintln!("{}", message),
+            }
+        }
+
+        match mode {
+            ProgressMode::Bar => {
+                if let Some(bar) = &bar {
+                    draw(bar, &totals, &event);
+                }
+            }
+            ProgressMode::Json => {
+                if let Ok(line) = serde_json::to_string(&event) {
+                    println!("{}", line);
+                }
+            }
+            ProgressMode::None => {}
+        }
+    }
+
+    if let Some(bar) = bar {
+        bar.finish_and_clear();
+    }
+
+    totals
+}
+
+fn draw(bar: &ProgressBar, totals: &Progress, event: &ProgressEvent) {
+    match event {
+        ProgressEvent::ScanFinished { bytes, .. } | ProgressEvent::RestoreStarted { bytes, .. } => {
+            bar.set_length(*bytes);
+        }
+        ProgressEvent::ChunkProcessed { path, .. } => {
+            bar.set_position(totals.bytes_chunked);
+            bar.set_message(format!(
+                "new {} reused {} {}",
+                totals.chunks_new,
+                totals.chunks_reused,
+                path.display()
+            ));
+        }
+        ProgressEvent::Written { path, .. } => {
+            bar.set_position(totals.bytes_written);
+            bar.set_message(path.display().to_string());
+        }
+        ProgressEvent::FileScanned { .. } => {
+            bar.set_message(format!("scanned {} files", totals.files_scanned));
+        }
+        ProgressEvent::Uploaded { .. }
+        | ProgressEvent::Checkpoint { .. }
+        | ProgressEvent::ChunkMapDropped { .. } => {}
+    }
+}
@@ -1,65 +1,217 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::Debug,
     fs,
-    io::{self, Cursor},
-    path::{Path, PathBuf},
+    io::{self, Cursor, Read},
+    path::{Component, Path, PathBuf},
 };
 
-use tokio::io::AsyncReadExt;
+use bytes::Bytes;
+use tokio::{io::AsyncReadExt, time::Instant};
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
 use walkdir::WalkDir;
 
-use crate::{metadata, reader, storage};
+use super::interrupt;
+use crate::{
+    crypto::ChunkIdKey,
+    location::Location,
+    metadata,
+    progress::{self, ProgressEvent, ProgressSender},
+    reader, storage,
+};
+
+/// New chunks are stored together once this many bytes or chunks are
+/// waiting.
+const BATCH_MAX_BYTES: usize = 4 * 1024 * 1024;
+const BATCH_MAX_CHUNKS: usize = 256;
+
+#[derive(Debug, Clone, Default)]
+pub struct BackupOptions {
+    /// Snapshot whose chunks are reused instead of being stored again.
+    pub base_key: Option<Location>,
+    pub config: reader::ChunkerConfig,
+    pub progress: Option<ProgressSender>,
+    /// Store an incomplete snapshot every time this many bytes have been
+    /// chunked. Its key is reported as [`ProgressEvent::Checkpoint`].
+    pub checkpoint_interval: Option<u64>,
+    /// File replaced with the key of every checkpoint as soon as it is
+    /// stored, to be passed back as `resume_key` after an interruption.
+    pub checkpoint_file: Option<PathBuf>,
+    /// Checkpoint of an interrupted backup to pick up from. Its chunks are
+    /// reused and files it fully covers are not read again.
+    pub resume_key: Option<Location>,
+    /// Stops the backup at the next chunk boundary after storing a
+    /// checkpoint, failing with [`Interrupted`](super::Interrupted).
+    pub cancel: CancellationToken,
+    pub deadline: Option<Instant>,
+    /// Identifies chunks by their blake3 keyed hash with this key instead of
+    /// their plain hash, for repositories whose content must not be
+    /// confirmable from known files. Base and resumed snapshots must have
+    /// used the same key.
+    pub hash_key: Option<ChunkIdKey>,
+    /// File listing the chunks earlier backups stored, reused like those of
+    /// the base snapshot and updated at every checkpoint and at the end. It
+    /// lets backups that cannot read earlier snapshots, e.g. of write-only
+    /// repositories, deduplicate anyway. Chunks must not be removed from the
+    /// repository behind its back.
+    pub chunk_map_file: Option<PathBuf>,
+    /// Only reads the chunk map, for dry runs whose chunks are never stored.
+    pub chunk_map_read_only: bool,
+}
 
 enum ChunkStatus {
     Available(metadata::Chunk),
     Reuse(u32),
 }
 
-#[instrument(skip(storage), ret, err)]
+type DedupCache = HashMap<[u8; 32], ChunkStatus>;
+
+#[instrument(skip(storage, options), fields(base_key = ?options.base_key), ret, err)]
 pub async fn backup<P: AsRef<Path> + Debug, S: storage::StoragePut + storage::StorageGet>(
     root: P,
-    base_key: Option<String>,
     storage: S,
-    config: reader::ChunkerConfig,
-) -> io::Result<String> {
-    let mut dedup_cache = match base_key {
-        Some(v) => {
-            let mut reader = storage.get(&v).await?;
-            let mut buffer = Vec::new();
-            reader.read_to_end(&mut buffer).await?;
-            let snapshot: metadata::Snapshot = bincode::deserialize(buffer.as_slice())
-                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-
-            let map: HashMap<[u8; 32], ChunkStatus> = snapshot
-                .chunks
-                .into_iter()
-                .map(|v| (v.hash, ChunkStatus::Available(v)))
-                .collect();
-
-            Some(map)
+    options: BackupOptions,
+) -> io::Result<Location> {
+    let mut session = Session::new(&storage, &options).await?;
+
+    let paths = WalkDir::new(&root)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|v| v.map(|v| v.into_path()).ok());
+
+    let paths = collect_files(
+        root.as_ref(),
+        paths,
+        &mut session.snapshot,
+        &options.progress,
+    )?;
+    let paths = session.resume(paths)?;
+
+    let chunker = reader::Chunker::new(paths, options.config.clone())?;
+    session.store_chunks(root.as_ref(), chunker).await?;
+
+    session.finish().await
+}
+
+/// Backs up exactly the given `paths` instead of walking `root`, e.g. a list
+/// produced by `find -print0`. Relative paths are resolved against `root` and
+/// every path must live under it.
+#[instrument(skip(paths, storage, options), fields(base_key = ?options.base_key), ret, err)]
+pub async fn backup_list<P: AsRef<Path> + Debug, S: storage::StoragePut + storage::StorageGet>(
+    root: P,
+    paths: Vec<PathBuf>,
+    storage: S,
+    options: BackupOptions,
+) -> io::Result<Location> {
+    let mut session = Session::new(&storage, &options).await?;
+
+    let root = root.as_ref();
+    let canonical_root = fs::canonicalize(root)?;
+
+    let mut seen = HashSet::new();
+    let mut list = Vec::with_capacity(paths.len());
+    for path in paths {
+        let path = normalize_list_path(root, &canonical_root, &path)?;
+        if seen.insert(path.clone()) {
+            list.push(path);
         }
-        None => None,
-    };
+    }
 
-    let mut snapshot = metadata::Snapshot {
-        files: Vec::new(),
-        chunks: Vec::new(),
-        file_chunks: Vec::new(),
-        file_symlink: Vec::new(),
+    let paths = collect_files(
+        root,
+        list.into_iter(),
+        &mut session.snapshot,
+        &options.progress,
+    )?;
+    let paths = session.resume(paths)?;
+
+    let chunker = reader::Chunker::new(paths, options.config.clone())?;
+    session.store_chunks(root, chunker).await?;
+
+    session.finish().await
+}
+
+/// Resolves a listed `path` against `root` and makes sure it stays under it:
+/// `..` components are refused and the directory holding the entry must not
+/// lead out of the root through a symlink. The entry itself is not followed,
+/// so symlinks are still recorded as such.
+fn normalize_list_path(root: &Path, canonical_root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let outside = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is outside of {}", path.display(), root.display()),
+        )
     };
 
-    let mut inode_map: HashMap<FileId, PathBuf> = HashMap::new();
+    if path.components().any(|v| v == Component::ParentDir) {
+        return Err(outside());
+    }
 
-    let paths = WalkDir::new(&root)
-        .sort_by_file_name()
-        .into_iter()
-        .filter_map(|v| v.map(|v| v.into_path()).ok())
-        .collect::<Vec<_>>();
+    let path: PathBuf = root.join(path).components().collect();
+    let relative_path = path.strip_prefix(root).map_err(|_| outside())?;
+
+    if let Some(parent) = relative_path.parent() {
+        let canonical_parent = fs::canonicalize(root.join(parent))?;
+        if !canonical_parent.starts_with(canonical_root) {
+            return Err(outside());
+        }
+    }
+
+    Ok(path)
+}
+
+/// Backs up everything read from `reader` as a single file named `path` in
+/// the snapshot, e.g. the standard input or the output of a spawned command.
+/// When resuming, the stream is read again but chunks of the checkpoint are
+/// not stored twice.
+#[instrument(skip(reader, storage, options), fields(base_key = ?options.base_key), ret, err)]
+pub async fn backup_stream<
+    R: Read + Send + 'static,
+    S: storage::StoragePut + storage::StorageGet,
+>(
+    path: PathBuf,
+    reader: R,
+    storage: S,
+    options: BackupOptions,
+) -> io::Result<Location> {
+    let mut session = Session::new(&storage, &options).await?;
+
+    session.snapshot.files.push(metadata::File {
+        path: path.clone(),
+        stat: None,
+    });
+
+    progress::report(
+        &options.progress,
+        ProgressEvent::FileScanned {
+            path: path.clone(),
+            size: None,
+        },
+    );
+    progress::report(
+        &options.progress,
+        ProgressEvent::ScanFinished { files: 1, bytes: 0 },
+    );
+
+    let chunker = reader::Chunker::from_reader(path, reader, options.config.clone());
+    session.store_chunks(Path::new(""), chunker).await?;
+
+    session.finish().await
+}
+
+/// Records symlinks and hard links of `paths` into `snapshot` and returns the
+/// regular files that still have to be chunked, in snapshot order.
+fn collect_files<I: Iterator<Item = PathBuf>>(
+    root: &Path,
+    paths: I,
+    snapshot: &mut metadata::Snapshot,
+    progress: &Option<ProgressSender>,
+) -> io::Result<Vec<PathBuf>> {
+    let mut inode_map: HashMap<metadata::FileId, PathBuf> = HashMap::new();
+    let mut bytes = 0;
 
     let paths = paths
-        .into_iter()
         .map(|path| {
             if path.is_dir() {
                 return Ok(None);
@@ -68,8 +220,8 @@ pub async fn backup<P: AsRef<Path> + Debug, S: storage::StoragePut + storage::St
             let meta = fs::symlink_metadata(&path)?;
 
             let relative_path = path
-                .strip_prefix(&root)
-                .map_err(|e| io::Error::other(e))?
+                .strip_prefix(root)
+                .map_err(io::Error::other)?
                 .to_path_buf();
 
             if meta.is_symlink() {
@@ -81,7 +233,7 @@ pub async fn backup<P: AsRef<Path> + Debug, S: storage::StoragePut + storage::St
                 return Ok(None);
             }
 
-            let is_new_file = FileId::from_metadata(&meta)
+            let is_new_file = metadata::FileId::from_metadata(&meta)
                 .map(|file_id| {
                     if let Some(existing_relative_path) = inode_map.get(&file_id) {
                         snapshot.file_symlink.push(metadata::FileSymlink {
@@ -99,8 +251,18 @@ pub async fn backup<P: AsRef<Path> + Debug, S: storage::StoragePut + storage::St
                 .unwrap_or(true);
 
             if is_new_file {
+                bytes += meta.len();
+                progress::report(
+                    progress,
+                    ProgressEvent::FileScanned {
+                        path: relative_path.clone(),
+                        size: Some(meta.len()),
+                    },
+                );
+
                 snapshot.files.push(metadata::File {
                     path: relative_path.clone(),
+                    stat: Some(metadata::FileStat::from_metadata(&meta)),
                 });
                 return Ok(Some(path));
             }
@@ -110,139 +272,415 @@ pub async fn backup<P: AsRef<Path> + Debug, S: storage::StoragePut + storage::St
         .filter_map(|v| v.transpose())
         .collect::<io::Result<Vec<_>>>()?;
 
-    let chunker = reader::Chunker::new(paths, config)?;
+    progress::report(
+        progress,
+        ProgressEvent::ScanFinished {
+            files: paths.len() as u64,
+            bytes,
+        },
+    );
+
+    Ok(paths)
+}
+
+struct Session<'a, S> {
+    storage: &'a S,
+    options: &'a BackupOptions,
+    snapshot: metadata::Snapshot,
+    dedup_cache: DedupCache,
+    checkpoint: Option<metadata::Snapshot>,
+    bytes_since_checkpoint: u64,
+    /// New chunks not stored yet, with their index in `snapshot.chunks`,
+    /// whose location is filled in once they are.
+    pending: Vec<(u32, Bytes)>,
+    pending_bytes: usize,
+}
+
+impl<'a, S: storage::StoragePut + storage::StorageGet> Session<'a, S> {
+    async fn new(storage: &'a S, options: &'a BackupOptions) -> io::Result<Self> {
+        let mut dedup_cache = DedupCache::new();
+
+        if let Some(key) = &options.base_key {
+            let snapshot = load_snapshot(key, storage).await?;
+            for chunk in snapshot.chunks {
+                dedup_cache.insert(chunk.hash, ChunkStatus::Available(chunk));
+            }
+        }
+
+        if let Some(path) = &options.chunk_map_file {
+            for chunk in load_chunk_map(path, &options.progress)? {
+                dedup_cache.insert(chunk.hash, ChunkStatus::Available(chunk));
+            }
+        }
+
+        let checkpoint = match &options.resume_key {
+            Some(key) => {
+                let snapshot = load_snapshot(key, storage).await?;
+                for chunk in snapshot.chunks.iter() {
+                    dedup_cache.insert(chunk.hash, ChunkStatus::Available(chunk.clone()));
+                }
+
+                Some(snapshot)
+            }
+            None => None,
+        };
+
+        let snapshot = metadata::Snapshot {
+            files: Vec::new(),
+            chunks: Vec::new(),
+            file_chunks: Vec::new(),
+            file_symlink: Vec::new(),
+            incomplete: false,
+        };
 
-    let mut current_file_index = 0;
-    for chunk in chunker {
-        let mut chunk = chunk?;
+        Ok(Self {
+            storage,
+            options,
+            snapshot,
+            dedup_cache,
+            checkpoint,
+            bytes_since_checkpoint: 0,
+            pending: Vec::new(),
+            pending_bytes: 0,
+        })
+    }
 
-        let buffer = {
-            let mut buffer = Vec::new();
-            let n = chunk.reader.read_to_end(&mut buffer).await?;
-            buffer.truncate(n);
+    /// Copies the file chunks of every file the checkpoint fully covers and
+    /// returns the remaining `paths`, which are parallel to
+    /// `self.snapshot.files`.
+    fn resume(&mut self, paths: Vec<PathBuf>) -> io::Result<Vec<PathBuf>> {
+        let checkpoint = match self.checkpoint.take() {
+            Some(v) => v,
+            None => return Ok(paths),
+        };
 
-            buffer
+        // The last file touched by the checkpoint may have been cut short.
+        let completed = match checkpoint.file_chunks.last() {
+            Some(v) => v.file_index as usize,
+            None => return Ok(paths),
         };
 
-        let hash = *blake3::hash(&buffer).as_bytes();
+        let mut file_chunks: HashMap<&Path, (&metadata::File, Vec<&metadata::FileChunk>)> =
+            HashMap::new();
+        for file_chunk in checkpoint.file_chunks.iter() {
+            let index = file_chunk.file_index as usize;
+            if index >= completed {
+                continue;
+            }
 
-        let chunk_index = {
-            let ref_index = match dedup_cache.as_mut() {
-                Some(map) => match map.get(&hash) {
-                    Some(ChunkStatus::Available(chunk)) => {
-                        let index = snapshot.chunks.len() as u32;
-                        snapshot.chunks.push(chunk.clone());
-                        let _ = map.insert(hash, ChunkStatus::Reuse(index));
+            let file = checkpoint.files.get(index).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "file metadata not found for given file chunk",
+                )
+            })?;
+            file_chunks
+                .entry(&file.path)
+                .or_insert_with(|| (file, Vec::new()))
+                .1
+                .push(file_chunk);
+        }
 
-                        Some(index)
-                    }
-                    Some(ChunkStatus::Reuse(index)) => Some(*index),
-                    None => None,
-                },
-                None => None,
+        let mut remaining = Vec::with_capacity(paths.len());
+        for (file_index, path) in paths.into_iter().enumerate() {
+            let file = &self.snapshot.files[file_index];
+            let relative_path = file.path.as_path();
+
+            // A file is skipped only if it is unchanged since the checkpoint
+            // and the checkpoint holds all of its bytes.
+            let done = match (file_chunks.get(relative_path), file.stat) {
+                (Some((checkpoint_file, chunks)), Some(stat)) => {
+                    let length: u64 = chunks.iter().map(|v| u64::from(v.length)).sum();
+                    checkpoint_file.stat == Some(stat) && stat.size == length
+                }
+                _ => false,
             };
 
-            match ref_index {
-                Some(index) => index,
-                None => {
-                    let index = snapshot.chunks.len() as u32;
-                    let len = buffer.len() as u64;
+            if !done {
+                remaining.push(path);
+                continue;
+            }
 
-                    let key = {
-                        let reader = Box::new(Cursor::new(buffer));
-                        let key = storage.put(reader, len).await?;
+            for file_chunk in file_chunks[relative_path].1.iter() {
+                let chunk = checkpoint
+                    .chunks
+                    .get(file_chunk.chunk_index as usize)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "chunk metadata not found for given file chunk",
+                        )
+                    })?;
+                let chunk_index = self
+                    .reuse_chunk(&chunk.hash)
+                    .ok_or_else(|| io::Error::other("checkpoint chunk missing from cache"))?;
+
+                self.snapshot.file_chunks.push(metadata::FileChunk {
+                    chunk_index,
+                    file_index: file_index as u32,
+                    ..(*file_chunk).clone()
+                });
+            }
+        }
+
+        Ok(remaining)
+    }
+
+    fn reuse_chunk(&mut self, hash: &[u8; 32]) -> Option<u32> {
+        match self.dedup_cache.get(hash) {
+            Some(ChunkStatus::Available(chunk)) => {
+                let index = self.snapshot.chunks.len() as u32;
+                self.snapshot.chunks.push(chunk.clone());
+                let _ = self.dedup_cache.insert(*hash, ChunkStatus::Reuse(index));
+
+                Some(index)
+            }
+            Some(ChunkStatus::Reuse(index)) => Some(*index),
+            None => None,
+        }
+    }
+
+    async fn store_chunks(&mut self, root: &Path, chunker: reader::Chunker) -> io::Result<()> {
+        let progress = &self.options.progress;
+
+        let mut current_file_index = 0;
+        for chunk in chunker {
+            if let Some(reason) = interrupt::check(&self.options.cancel, self.options.deadline) {
+                self.store_checkpoint().await?;
+                return Err(reason.into());
+            }
+
+            let mut chunk = chunk?;
+
+            let buffer = {
+                let mut buffer = Vec::new();
+                let n = chunk.reader.read_to_end(&mut buffer).await?;
+                buffer.truncate(n);
+
+                buffer
+            };
 
-                        key
-                    };
+            let hash = super::chunk_id(self.options.hash_key.as_ref(), &buffer);
+            let length = buffer.len() as u64;
+            let mut reused = true;
 
-                    snapshot.chunks.push(metadata::Chunk {
+            let chunk_index = match self.reuse_chunk(&hash) {
+                Some(index) => index,
+                None => {
+                    let index = self.snapshot.chunks.len() as u32;
+                    reused = false;
+
+                    self.snapshot.chunks.push(metadata::Chunk {
                         hash,
-                        location: key,
+                        location: Location::default(),
+                        checksum: Some(*blake3::hash(&buffer).as_bytes()),
                     });
+                    let _ = self.dedup_cache.insert(hash, ChunkStatus::Reuse(index));
+
+                    self.pending_bytes += buffer.len();
+                    self.pending.push((index, Bytes::from(buffer)));
+                    if self.pending_bytes >= BATCH_MAX_BYTES
+                        || self.pending.len() >= BATCH_MAX_CHUNKS
+                    {
+                        self.store_pending().await?;
+                    }
 
                     index
                 }
+            };
+
+            if let Some(source) = chunk.sources.first() {
+                progress::report(
+                    progress,
+                    ProgressEvent::ChunkProcessed {
+                        path: source
+                            .path
+                            .strip_prefix(root)
+                            .map_err(io::Error::other)?
+                            .to_path_buf(),
+                        length,
+                        reused,
+                    },
+                );
             }
-        };
 
-        let mut chunk_offset = 0;
-        for source in chunk.sources {
-            let source_rel_path = source
-                .path
-                .strip_prefix(&root)
-                .map_err(|e| io::Error::other(e))?;
+            let files = &self.snapshot.files;
+
+            let mut chunk_offset = 0;
+            for source in chunk.sources {
+                let source_rel_path = source.path.strip_prefix(root).map_err(io::Error::other)?;
 
-            while current_file_index < snapshot.files.len() {
-                if snapshot.files[current_file_index].path == source_rel_path {
-                    break;
+                while current_file_index < files.len() {
+                    if files[current_file_index].path == source_rel_path {
+                        break;
+                    }
+                    current_file_index += 1;
+                }
+
+                if current_file_index >= files.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "Chunk source path mismatch: Chunker yielded a file not in snapshot",
+                    ));
                 }
-                current_file_index += 1;
+
+                self.snapshot.file_chunks.push(metadata::FileChunk {
+                    chunk_index,
+                    file_index: current_file_index as u32,
+                    chunk_offset,
+                    file_offset: source.offset,
+                    length: source.length,
+                });
+
+                chunk_offset += source.length;
             }
 
-            if current_file_index >= snapshot.files.len() {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    "Chunk source path mismatch: Chunker yielded a file not in snapshot",
-                ));
+            self.bytes_since_checkpoint += length;
+            if let Some(interval) = self.options.checkpoint_interval
+                && self.bytes_since_checkpoint >= interval
+            {
+                self.store_checkpoint().await?;
             }
+        }
 
-            snapshot.file_chunks.push(metadata::FileChunk {
-                chunk_index,
-                file_index: current_file_index as u32,
-                chunk_offset: chunk_offset,
-                file_offset: source.offset,
-                length: source.length,
-            });
+        Ok(())
+    }
 
-            chunk_offset += source.length;
+    async fn store_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
         }
+
+        let (indices, objects): (Vec<_>, Vec<_>) =
+            std::mem::take(&mut self.pending).into_iter().unzip();
+        self.pending_bytes = 0;
+
+        let keys = self
+            .storage
+            .put_batch(&objects, storage::ObjectKind::Chunk)
+            .await?;
+
+        for ((index, object), key) in indices.into_iter().zip(objects.iter()).zip(keys) {
+            self.snapshot.chunks[index as usize].location = key;
+            progress::report(
+                &self.options.progress,
+                ProgressEvent::Uploaded {
+                    length: object.len() as u64,
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn store_checkpoint(&mut self) -> io::Result<()> {
+        self.store_pending().await?;
+
+        self.snapshot.incomplete = true;
+        let key = store_snapshot(&self.snapshot, self.storage, &self.options.progress).await;
+        self.snapshot.incomplete = false;
+        let key = key?;
+
+        if let Some(path) = &self.options.checkpoint_file {
+            save_checkpoint(path, &key)?;
+        }
+        self.save_chunk_map()?;
+
+        progress::report(&self.options.progress, ProgressEvent::Checkpoint { key });
+        self.bytes_since_checkpoint = 0;
+
+        Ok(())
+    }
+
+    async fn finish(mut self) -> io::Result<Location> {
+        self.store_pending().await?;
+
+        let key = store_snapshot(&self.snapshot, self.storage, &self.options.progress).await?;
+        self.save_chunk_map()?;
+
+        Ok(key)
     }
 
-    let key = {
-        let buffer = bincode::serialize(&snapshot).map_err(|e| io::Error::other(e))?;
+    /// Records every chunk known to be stored in the chunk map, once they
+    /// were flushed along with a snapshot.
+    fn save_chunk_map(&self) -> io::Result<()> {
+        let Some(path) = &self.options.chunk_map_file else {
+            return Ok(());
+        };
+        if self.options.chunk_map_read_only {
+            return Ok(());
+        }
+
+        let available = self.dedup_cache.values().filter_map(|v| match v {
+            ChunkStatus::Available(chunk) => Some(chunk),
+            ChunkStatus::Reuse(_) => None,
+        });
+        let chunks: Vec<_> = self.snapshot.chunks.iter().chain(available).collect();
 
-        let len = buffer.len() as u64;
-        let reader = Box::new(Cursor::new(buffer));
-        let key = storage.put(reader, len).await?;
+        let buffer = bincode::serialize(&chunks).map_err(io::Error::other)?;
+        super::replace_file(path, &buffer)
+    }
+}
 
-        key
+/// Reads the chunk map at `path`, which is empty until a first backup wrote
+/// it. An unreadable map is dropped, costing only deduplication, and reported
+/// as [`ProgressEvent::ChunkMapDropped`].
+fn load_chunk_map(
+    path: &Path,
+    progress: &Option<ProgressSender>,
+) -> io::Result<Vec<metadata::Chunk>> {
+    let buffer = match fs::read(path) {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
     };
 
-    Ok(key)
+    Ok(bincode::deserialize(&buffer).unwrap_or_else(|e| {
+        tracing::warn!("dropping unreadable chunk map {}: {}", path.display(), e);
+        progress::report(
+            progress,
+            ProgressEvent::ChunkMapDropped {
+                path: path.to_path_buf(),
+                reason: e.to_string(),
+            },
+        );
+        Vec::new()
+    }))
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
-struct FileId {
-    volume_id: u64,
-    file_index: u64,
+fn save_checkpoint(path: &Path, key: &Location) -> io::Result<()> {
+    super::replace_file(path, key.to_string().as_bytes())
 }
 
-impl FileId {
-    #[cfg(not(any(unix, windows)))]
-    fn from_metadata(_meta: &fs::Metadata) -> Option<FileId> {
-        None
-    }
+async fn load_snapshot<S: storage::StorageGet>(
+    key: &Location,
+    storage: &S,
+) -> io::Result<metadata::Snapshot> {
+    let mut reader = storage.get(key, storage::ObjectKind::Snapshot).await?;
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).await?;
 
-    #[cfg(unix)]
-    fn from_metadata(meta: &fs::Metadata) -> Option<FileId> {
-        use std::os::unix::fs::MetadataExt;
-        Some(FileId {
-            volume_id: meta.dev(),
-            file_index: meta.ino(),
-        })
-    }
+    metadata::Snapshot::decode(&buffer)
+}
 
-    #[cfg(all(windows, feature = "experimental"))]
-    fn from_metadata(meta: &fs::Metadata) -> Option<FileId> {
-        use std::os::windows::fs::MetadataExt;
-        Some(FileId {
-            volume_id: meta.volume_serial_number()?.into(),
-            file_index: meta.file_index()?,
-        })
-    }
+async fn store_snapshot<S: storage::StoragePut>(
+    snapshot: &metadata::Snapshot,
+    storage: &S,
+    progress: &Option<ProgressSender>,
+) -> io::Result<Location> {
+    let buffer = snapshot.encode()?;
 
-    #[cfg(all(windows, not(feature = "experimental")))]
-    fn from_metadata(_meta: &fs::Metadata) -> Option<FileId> {
-        None
-    }
+    let len = buffer.len() as u64;
+    let reader = Box::new(Cursor::new(buffer));
+
+    let key = storage
+        .put(reader, len, storage::ObjectKind::Snapshot)
+        .await?;
+    progress::report(progress, ProgressEvent::Uploaded { length: len });
+
+    // The key is handed out to be recorded, so everything it references must
+    // survive a crash from now on.
+    storage.flush().await?;
+
+    Ok(key)
 }
@@ -0,0 +1,53 @@
+use std::{error::Error, fmt, io};
+
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Error wrapped in the `io::Error` returned when a command stops early.
+/// `Cancelled` uses `ErrorKind::Interrupted`, `DeadlineExceeded` uses
+/// `ErrorKind::TimedOut`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupted {
+    Cancelled,
+    DeadlineExceeded,
+}
+
+impl Interrupted {
+    /// Returns why `err` was raised if it comes from an interruption.
+    pub fn from_io_error(err: &io::Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref::<Self>().copied()
+    }
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Interrupted::Cancelled => write!(f, "operation cancelled"),
+            Interrupted::DeadlineExceeded => write!(f, "operation deadline exceeded"),
+        }
+    }
+}
+
+impl Error for Interrupted {}
+
+impl From<Interrupted> for io::Error {
+    fn from(value: Interrupted) -> Self {
+        let kind = match value {
+            Interrupted::Cancelled => io::ErrorKind::Interrupted,
+            Interrupted::DeadlineExceeded => io::ErrorKind::TimedOut,
+        };
+
+        io::Error::new(kind, value)
+    }
+}
+
+pub(crate) fn check(cancel: &CancellationToken, deadline: Option<Instant>) -> Option<Interrupted> {
+    if cancel.is_cancelled() {
+        return Some(Interrupted::Cancelled);
+    }
+
+    match deadline {
+        Some(deadline) if Instant::now() >= deadline => Some(Interrupted::DeadlineExceeded),
+        _ => None,
+    }
+}
@@ -1,5 +1,34 @@
+use std::{fs, io, io::Write, path::Path};
+
+use crate::crypto::ChunkIdKey;
+
 mod backup;
+mod interrupt;
+mod recover;
 mod restore;
 
-pub use backup::backup;
-pub use restore::restore;
+pub use backup::{BackupOptions, backup, backup_list, backup_stream};
+pub use interrupt::Interrupted;
+pub use recover::{Recovery, recover};
+pub use restore::{RestoreOptions, restore};
+
+/// Replaces the file at `path` with `buffer` through a temp file named after
+/// it with `.tmp` appended, synced before the rename so a crash leaves either
+/// the old or the new content.
+pub fn replace_file(path: &Path, buffer: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(buffer)?;
+    file.sync_all()?;
+    fs::rename(temp_path, path)
+}
+
+/// Identifies a chunk by its blake3 hash, keyed with `hash_key` if set.
+fn chunk_id(hash_key: Option<&ChunkIdKey>, buffer: &[u8]) -> [u8; 32] {
+    match hash_key {
+        Some(key) => *blake3::keyed_hash(key.as_bytes(), buffer).as_bytes(),
+        None => *blake3::hash(buffer).as_bytes(),
+    }
+}
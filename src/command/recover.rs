@@ -0,0 +1,65 @@
+use std::io;
+
+use tokio::io::AsyncReadExt;
+
+use crate::{
+    location::Location,
+    metadata,
+    storage::{self, StorageGet},
+};
+
+/// What [`recover`] found in a blob file.
+#[derive(Debug, Clone, Default)]
+pub struct Recovery {
+    /// The complete snapshots, in the order they were written.
+    pub snapshots: Vec<Location>,
+    /// Snapshot records that could not be opened or decoded.
+    pub skipped: usize,
+}
+
+/// Finds the complete snapshots stored in a blob file, in the order they were
+/// written, so the index of a repository can be rebuilt from the blob alone.
+/// Snapshots are read through `storage`, which wraps `blob` when its objects
+/// are encrypted. Records that fail authentication or do not decode are
+/// skipped with a warning, so one damaged record does not hide the others.
+pub async fn recover<const WRITE: bool, S: StorageGet>(
+    blob: &storage::BlobFileStorage<WRITE>,
+    storage: &S,
+) -> io::Result<Recovery> {
+    let mut recovery = Recovery::default();
+
+    for record in blob.records().await? {
+        if record.kind == storage::ObjectKind::Chunk {
+            continue;
+        }
+
+        let snapshot = match read_snapshot(storage, &record).await {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                tracing::warn!("skipping unreadable snapshot {}: {}", record.location, e);
+                recovery.skipped += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        if snapshot.incomplete {
+            tracing::debug!("skipping checkpoint snapshot {}", record.location);
+        } else {
+            recovery.snapshots.push(record.location);
+        }
+    }
+
+    Ok(recovery)
+}
+
+async fn read_snapshot<S: StorageGet>(
+    storage: &S,
+    record: &storage::BlobRecord,
+) -> io::Result<metadata::Snapshot> {
+    let mut reader = storage.get(&record.location, record.kind).await?;
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).await?;
+
+    metadata::Snapshot::decode(&buffer)
+}
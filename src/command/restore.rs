@@ -2,81 +2,263 @@ use std::{io, path::Path};
 
 use tokio::{
     fs,
-    io::{AsyncReadExt, AsyncSeekExt},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    time::Instant,
 };
+use tokio_util::sync::CancellationToken;
 
+use super::interrupt;
 use crate::{
+    crypto::ChunkIdKey,
+    location::Location,
     metadata,
-    reader::{self, StreamReadSeeker},
+    progress::{self, ProgressEvent, ProgressSender},
+    reader::StreamReadSeeker,
     storage, writer,
 };
 
+/// Appended to the name of a file while it is being restored.
+const PARTIAL_SUFFIX: &str = ".lepatch-partial";
+/// Chunks fetched with one [`get_batch`](storage::StorageGet::get_batch).
+const BATCH_MAX_CHUNKS: usize = 64;
+
+#[derive(Debug, Clone, Default)]
+pub struct RestoreOptions {
+    pub progress: Option<ProgressSender>,
+    /// Stops the restore before the next chunk, removing the partially
+    /// written file and failing with [`Interrupted`](super::Interrupted).
+    pub cancel: CancellationToken,
+    pub deadline: Option<Instant>,
+    /// Key the chunk ids were computed with, as
+    /// [`BackupOptions::hash_key`](super::BackupOptions::hash_key). Every
+    /// chunk read whole is checked against its id.
+    pub hash_key: Option<ChunkIdKey>,
+}
+
 pub async fn restore<P: AsRef<Path>, S: storage::StorageGet>(
     root: P,
-    key: String,
+    key: Location,
     storage: S,
+    options: RestoreOptions,
 ) -> io::Result<()> {
     let snapshot = {
-        let mut reader: StreamReadSeeker = storage.get(&key).await?;
+        let mut reader: StreamReadSeeker = storage.get(&key, storage::ObjectKind::Snapshot).await?;
         let mut buffer = Vec::new();
         reader.read_to_end(&mut buffer).await?;
-        let snapshot: metadata::Snapshot = bincode::deserialize(buffer.as_slice())
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-        snapshot
+        metadata::Snapshot::decode(&buffer)?
     };
 
+    if snapshot.incomplete {
+        tracing::warn!("restoring an incomplete checkpoint snapshot");
+    }
+
     let root = root.as_ref();
 
+    progress::report(
+        &options.progress,
+        ProgressEvent::RestoreStarted {
+            files: snapshot.files.len() as u64,
+            bytes: snapshot
+                .file_chunks
+                .iter()
+                .map(|v| u64::from(v.length))
+                .sum(),
+        },
+    );
+
+    // Chunks are cut from consecutive file chunks, so the furthest end of
+    // those referencing a chunk is its length.
+    let mut chunk_lengths = vec![0u64; snapshot.chunks.len()];
+    let mut file_chunks: Vec<Vec<&metadata::FileChunk>> = vec![Vec::new(); snapshot.files.len()];
     for file_chunk in snapshot.file_chunks.iter() {
-        let file = snapshot
-            .files
-            .get(file_chunk.file_index as usize)
+        if let Some(length) = chunk_lengths.get_mut(file_chunk.chunk_index as usize) {
+            let end = u64::from(file_chunk.chunk_offset) + u64::from(file_chunk.length);
+            *length = (*length).max(end);
+        }
+
+        file_chunks
+            .get_mut(file_chunk.file_index as usize)
             .ok_or_else(|| {
                 io::Error::new(
                     io::ErrorKind::InvalidData,
                     "file metadata not found for given file chunk",
                 )
-            })?;
-        let chunk = snapshot
-            .chunks
-            .get(file_chunk.chunk_index as usize)
-            .ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "chunk metadata not found for given file chunk",
-                )
-            })?;
+            })?
+            .push(file_chunk);
+    }
 
-        let mut file = {
-            let file_path = root.join(&file.path);
+    for (file, file_chunks) in snapshot.files.iter().zip(file_chunks) {
+        if let Some(reason) = interrupt::check(&options.cancel, options.deadline) {
+            return Err(reason.into());
+        }
 
-            let parent = &file_path
-                .parent()
-                .ok_or_else(|| io::Error::other("internal error, file parent not found"))?;
-            fs::create_dir_all(parent).await?;
+        let file_path = root.join(&file.path);
 
-            let mut file: fs::File = fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(&file_path)
-                .await?;
+        let parent = &file_path
+            .parent()
+            .ok_or_else(|| io::Error::other("internal error, file parent not found"))?;
+        fs::create_dir_all(parent).await?;
 
-            file.seek(io::SeekFrom::Start(file_chunk.file_index.into()))
-                .await?;
+        let partial_path = {
+            let mut file_name = file_path
+                .file_name()
+                .ok_or_else(|| io::Error::other("internal error, file name not found"))?
+                .to_os_string();
+            file_name.push(PARTIAL_SUFFIX);
 
-            writer::SliceAsyncWriter::new(file, file_chunk.length.into())
+            file_path.with_file_name(file_name)
         };
 
-        let mut chunk = {
-            let mut chunk: StreamReadSeeker = storage.get(&chunk.location).await?;
-            chunk
-                .seek(io::SeekFrom::Start(file_chunk.chunk_offset.into()))
+        let result = restore_file(
+            &partial_path,
+            &file.path,
+            file_chunks,
+            &snapshot,
+            &chunk_lengths,
+            &storage,
+            &options,
+        )
+        .await;
+
+        match result {
+            Ok(()) => fs::rename(&partial_path, &file_path).await?,
+            Err(e) => {
+                let _ = fs::remove_file(&partial_path).await;
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn restore_file<S: storage::StorageGet>(
+    partial_path: &Path,
+    path: &Path,
+    file_chunks: Vec<&metadata::FileChunk>,
+    snapshot: &metadata::Snapshot,
+    chunk_lengths: &[u64],
+    storage: &S,
+    options: &RestoreOptions,
+) -> io::Result<()> {
+    let mut file: fs::File = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(partial_path)
+        .await?;
+
+    for batch in file_chunks.chunks(BATCH_MAX_CHUNKS) {
+        if let Some(reason) = interrupt::check(&options.cancel, options.deadline) {
+            return Err(reason.into());
+        }
+
+        let chunks = batch
+            .iter()
+            .map(|file_chunk| {
+                snapshot
+                    .chunks
+                    .get(file_chunk.chunk_index as usize)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "chunk metadata not found for given file chunk",
+                        )
+                    })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // A file chunk covering its whole chunk is fetched with the others
+        // of the batch and checked against the chunk's checksum and id. Only
+        // its part of any other chunk is read.
+        let whole: Vec<bool> = batch
+            .iter()
+            .map(|v| {
+                v.chunk_offset == 0
+                    && chunk_lengths.get(v.chunk_index as usize) == Some(&u64::from(v.length))
+            })
+            .collect();
+
+        let keys: Vec<_> = chunks
+            .iter()
+            .zip(&whole)
+            .filter(|(_, whole)| **whole)
+            .map(|(v, _)| v.location.clone())
+            .collect();
+        let mut buffers = storage
+            .get_batch(&keys, storage::ObjectKind::Chunk)
+            .await?
+            .into_iter();
+
+        for ((file_chunk, chunk), whole) in batch.iter().zip(chunks).zip(whole) {
+            file.seek(io::SeekFrom::Start(file_chunk.file_offset))
                 .await?;
 
-            reader::SliceAsyncReader::new(chunk, file_chunk.length.into())
-        };
+            let length = u64::from(file_chunk.length);
+            if whole {
+                let buffer = buffers
+                    .next()
+                    .ok_or_else(|| io::Error::other("internal error, batch chunk missing"))?;
+                verify_chunk(chunk, &buffer, options)?;
 
-        tokio::io::copy(&mut chunk, &mut file).await?;
+                let data = buffer.get(..file_chunk.length as usize).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "file chunk lies outside of its chunk",
+                    )
+                })?;
+                file.write_all(data).await?;
+            } else {
+                let mut reader = storage
+                    .get_range(
+                        &chunk.location,
+                        file_chunk.chunk_offset.into(),
+                        length,
+                        storage::ObjectKind::Chunk,
+                    )
+                    .await?;
+                let mut writer = writer::SliceAsyncWriter::new(&mut file, length);
+
+                if tokio::io::copy(&mut reader, &mut writer).await? != length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "file chunk lies outside of its chunk",
+                    ));
+                }
+            }
+
+            progress::report(
+                &options.progress,
+                ProgressEvent::Written {
+                    path: path.to_path_buf(),
+                    length,
+                },
+            );
+        }
+    }
+
+    file.flush().await
+}
+
+fn verify_chunk(
+    chunk: &metadata::Chunk,
+    buffer: &[u8],
+    options: &RestoreOptions,
+) -> io::Result<()> {
+    if chunk
+        .checksum
+        .is_some_and(|v| v != *blake3::hash(buffer).as_bytes())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("chunk {} does not match its checksum", chunk.location),
+        ));
+    }
+    if super::chunk_id(options.hash_key.as_ref(), buffer) != chunk.hash {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("chunk {} does not match its id", chunk.location),
+        ));
     }
 
     Ok(())
@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::EncryptionConfig,
+    storage::{RetryPolicy, SyncPolicy},
+};
+
+/// Settings of a repository, stored next to its data. Missing fields take
+/// their default value so older files keep working.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct RepositoryConfig {
+    pub retry: RetryPolicy,
+    pub sync: SyncPolicy,
+    /// Set for repositories whose objects are encrypted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionConfig>,
+}
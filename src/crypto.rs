@@ -0,0 +1,679 @@
+use std::{
+    fmt::{self, Debug},
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, AeadCore, OsRng, Payload, rand_core::RngCore},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+use x25519_dalek::StaticSecret;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::storage::ObjectKind;
+
+pub const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+const SALT_LEN: usize = 16;
+const SLOT_ID_LEN: usize = 4;
+
+/// Context of the key derived from the master key for chunk ids.
+const CHUNK_ID_CONTEXT: &str = "lepatch 2026-10-18 chunk id";
+/// Context of the secret key derived from the master key in write-only
+/// repositories.
+const SECRET_KEY_CONTEXT: &str = "lepatch 2026-10-18 secret key";
+/// Context of the key an object sealed to a public key is encrypted with.
+const SEALED_TO_CONTEXT: &str = "lepatch 2026-10-18 sealed to public key";
+
+/// Starts every sealed object, so the format can change later.
+const SEALED_VERSION: u8 = 1;
+/// Starts objects sealed to a public key, followed by the ephemeral public
+/// key of the sender.
+const SEALED_TO_VERSION: u8 = 2;
+
+/// Key every object of an encrypted repository is sealed with. It is
+/// generated once and stored in the repository config wrapped by a key
+/// derived from each password, so passwords can be added, changed and
+/// removed without re-encrypting anything. Wiped from memory when dropped.
+#[derive(Clone)]
+pub struct MasterKey([u8; KEY_LEN]);
+
+impl Drop for MasterKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl MasterKey {
+    pub fn generate() -> Self {
+        let mut key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+
+        Self(key)
+    }
+
+    /// Key for blake3's keyed mode, so the chunk ids of a repository cannot be
+    /// computed from known content without it.
+    pub fn chunk_id_key(&self) -> ChunkIdKey {
+        ChunkIdKey(blake3::derive_key(CHUNK_ID_CONTEXT, &self.0))
+    }
+
+    /// Encrypts and authenticates `plaintext` with a random nonce, binding
+    /// it to `kind`.
+    pub fn seal(&self, plaintext: &[u8], kind: ObjectKind) -> io::Result<Vec<u8>> {
+        seal(&self.0, plaintext, kind_aad(kind))
+    }
+
+    /// Reverses [`seal`](Self::seal), failing with
+    /// [`InvalidData`](io::ErrorKind::InvalidData) if the object was sealed
+    /// with another key or as another kind, or modified since.
+    pub fn open(&self, sealed: &[u8], kind: ObjectKind) -> io::Result<Vec<u8>> {
+        open(&self.0, sealed, kind_aad(kind))
+    }
+
+    fn secret_key(&self) -> StaticSecret {
+        StaticSecret::from(blake3::derive_key(SECRET_KEY_CONTEXT, &self.0))
+    }
+
+    /// Public key of the key pair derived from the master key, which objects
+    /// of write-only repositories are sealed to.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(x25519_dalek::PublicKey::from(&self.secret_key()).to_bytes())
+    }
+}
+
+/// X25519 public key objects can be sealed to without being able to open
+/// them again.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey([u8; KEY_LEN]);
+
+impl Debug for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PublicKey({})", self)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|v| write!(f, "{:02x}", v))
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        hex::serialize(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        hex::deserialize(deserializer)?
+            .try_into()
+            .map(Self)
+            .map_err(|_| de::Error::custom("public key must be 32 bytes"))
+    }
+}
+
+impl PublicKey {
+    /// Encrypts and authenticates `plaintext` with a key agreed between a new
+    /// ephemeral key pair and this public key, binding it to `kind`.
+    pub fn seal(&self, plaintext: &[u8], kind: ObjectKind) -> io::Result<Vec<u8>> {
+        let mut ephemeral = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut ephemeral);
+        let ephemeral = StaticSecret::from(ephemeral);
+        let ephemeral_public = x25519_dalek::PublicKey::from(&ephemeral).to_bytes();
+
+        let shared = ephemeral.diffie_hellman(&x25519_dalek::PublicKey::from(self.0));
+        let key = self.sealing_key(shared.as_bytes(), &ephemeral_public);
+
+        let mut sealed = Vec::with_capacity(1 + KEY_LEN + NONCE_LEN + plaintext.len() + TAG_LEN);
+        sealed.push(SEALED_TO_VERSION);
+        sealed.extend_from_slice(&ephemeral_public);
+        encrypt(&key, plaintext, kind_aad(kind), &mut sealed)?;
+
+        Ok(sealed)
+    }
+
+    /// Reverses [`seal`](Self::seal) with the secret key of `master`, which
+    /// must be the key this public key was derived from.
+    fn open(&self, master: &MasterKey, sealed: &[u8], kind: ObjectKind) -> io::Result<Vec<u8>> {
+        let (ephemeral_public, body) = match sealed.split_first() {
+            Some((&SEALED_TO_VERSION, rest)) if rest.len() >= KEY_LEN => rest.split_at(KEY_LEN),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "object is not sealed to a public key",
+                ));
+            }
+        };
+        let ephemeral_public: [u8; KEY_LEN] =
+            ephemeral_public.try_into().expect("split at key length");
+
+        let shared = master
+            .secret_key()
+            .diffie_hellman(&x25519_dalek::PublicKey::from(ephemeral_public));
+        let key = self.sealing_key(shared.as_bytes(), &ephemeral_public);
+
+        decrypt(&key, body, kind_aad(kind))
+    }
+
+    fn sealing_key(
+        &self,
+        shared: &[u8; KEY_LEN],
+        ephemeral_public: &[u8; KEY_LEN],
+    ) -> Zeroizing<[u8; KEY_LEN]> {
+        let mut material = Zeroizing::new(Vec::with_capacity(3 * KEY_LEN));
+        material.extend_from_slice(shared);
+        material.extend_from_slice(ephemeral_public);
+        material.extend_from_slice(&self.0);
+
+        Zeroizing::new(blake3::derive_key(SEALED_TO_CONTEXT, &material))
+    }
+}
+
+/// Key chunk ids are computed with, derived from the master key. Backup
+/// hosts of write-only repositories hold a copy of it next to the public key,
+/// so they can deduplicate without being able to open anything. Wiped from
+/// memory when dropped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ChunkIdKey([u8; KEY_LEN]);
+
+impl Drop for ChunkIdKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl Debug for ChunkIdKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ChunkIdKey(..)")
+    }
+}
+
+impl Serialize for ChunkIdKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        hex::serialize(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ChunkIdKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = Zeroizing::new(hex::deserialize(deserializer)?);
+        bytes
+            .as_slice()
+            .try_into()
+            .map(Self)
+            .map_err(|_| de::Error::custom("chunk id key must be 32 bytes"))
+    }
+}
+
+impl ChunkIdKey {
+    pub fn from_bytes(bytes: [u8; KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.0
+    }
+}
+
+/// The keys objects of an encrypted repository are sealed and opened with.
+#[derive(Debug, Clone)]
+pub enum RepositoryKey {
+    /// Objects are sealed with the master key itself.
+    Master(MasterKey),
+    /// Objects are sealed to the public key, so backups need no password.
+    /// Opening them needs the master key the public key was derived from.
+    WriteOnly {
+        public_key: PublicKey,
+        chunk_id_key: ChunkIdKey,
+        master_key: Option<MasterKey>,
+    },
+}
+
+impl RepositoryKey {
+    /// Whether objects can be opened, not only sealed.
+    pub fn can_open(&self) -> bool {
+        !matches!(
+            self,
+            Self::WriteOnly {
+                master_key: None,
+                ..
+            }
+        )
+    }
+
+    pub fn chunk_id_key(&self) -> ChunkIdKey {
+        match self {
+            Self::Master(key) => key.chunk_id_key(),
+            Self::WriteOnly { chunk_id_key, .. } => chunk_id_key.clone(),
+        }
+    }
+
+    pub fn seal(&self, plaintext: &[u8], kind: ObjectKind) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Master(key) => key.seal(plaintext, kind),
+            Self::WriteOnly { public_key, .. } => public_key.seal(plaintext, kind),
+        }
+    }
+
+    /// Opens an object, failing with
+    /// [`PermissionDenied`](io::ErrorKind::PermissionDenied) without the
+    /// master key.
+    pub fn open(&self, sealed: &[u8], kind: ObjectKind) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Master(key) => key.open(sealed, kind),
+            Self::WriteOnly {
+                public_key,
+                master_key: Some(master_key),
+                ..
+            } => public_key.open(master_key, sealed, kind),
+            Self::WriteOnly {
+                master_key: None, ..
+            } => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "reading a write-only repository needs the password",
+            )),
+        }
+    }
+}
+
+/// Argon2id parameters deriving the key that wraps the master key from a
+/// password.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KdfParams {
+    #[serde(with = "hex")]
+    pub salt: Vec<u8>,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// Recommended parameters with a new random salt.
+    pub fn generate() -> Self {
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+
+        Self {
+            salt,
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+
+    /// Derives the key wrapping the master key, wiped when dropped.
+    pub fn derive(&self, password: &[u8]) -> io::Result<Zeroizing<[u8; KEY_LEN]>> {
+        let params = Params::new(
+            self.memory_kib,
+            self.iterations,
+            self.parallelism,
+            Some(KEY_LEN),
+        )
+        .map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid KDF parameters: {}", e),
+            )
+        })?;
+
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password, &self.salt, &mut *key)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid KDF input: {}", e),
+                )
+            })?;
+
+        Ok(key)
+    }
+}
+
+/// A copy of the master key wrapped with one password.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeySlot {
+    /// Random hex id naming the slot in commands.
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Seconds since the Unix epoch.
+    #[serde(default)]
+    pub created: u64,
+    pub kdf: KdfParams,
+    /// The master key sealed with the key derived from the password.
+    #[serde(with = "hex")]
+    pub wrapped_key: Vec<u8>,
+}
+
+impl KeySlot {
+    fn new(key: &MasterKey, password: &[u8], label: Option<String>) -> io::Result<Self> {
+        let mut id = [0u8; SLOT_ID_LEN];
+        OsRng.fill_bytes(&mut id);
+
+        let mut slot = Self {
+            id: id.iter().map(|v| format!("{:02x}", v)).collect(),
+            label,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|v| v.as_secs())
+                .unwrap_or(0),
+            kdf: KdfParams::generate(),
+            wrapped_key: Vec::new(),
+        };
+        slot.wrap(key, password)?;
+
+        Ok(slot)
+    }
+
+    /// Wraps `key` with `password` under a new salt.
+    fn wrap(&mut self, key: &MasterKey, password: &[u8]) -> io::Result<()> {
+        self.kdf = KdfParams::generate();
+        let wrapping_key = self.kdf.derive(password)?;
+        self.wrapped_key = seal(&wrapping_key, &key.0, &[])?;
+
+        Ok(())
+    }
+
+    fn unwrap(&self, password: &[u8]) -> io::Result<Option<MasterKey>> {
+        let wrapping_key = self.kdf.derive(password)?;
+        let key = match open(&wrapping_key, &self.wrapped_key, &[]) {
+            Ok(v) => Zeroizing::new(v),
+            Err(_) => return Ok(None),
+        };
+
+        let key = key.as_slice().try_into().map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "wrapped key has a wrong length")
+        })?;
+
+        Ok(Some(MasterKey(key)))
+    }
+}
+
+/// Encryption settings stored in the repository config: the master key
+/// wrapped once for every password allowed to open the repository.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionConfig {
+    /// Empty in configs exported for backup hosts.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub slots: Vec<KeySlot>,
+    /// Set for write-only repositories, whose objects are sealed to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<PublicKey>,
+    /// Set in configs exported for backup hosts of write-only repositories,
+    /// which compute chunk ids with it instead of the master key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_id_key: Option<ChunkIdKey>,
+}
+
+impl EncryptionConfig {
+    /// Wraps `key` with `password` in a first slot. Objects of write-only
+    /// repositories are sealed to the public key derived from `key`.
+    pub fn new(
+        key: &MasterKey,
+        password: &[u8],
+        label: Option<String>,
+        write_only: bool,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            slots: vec![KeySlot::new(key, password, label)?],
+            public_key: write_only.then(|| key.public_key()),
+            chunk_id_key: None,
+        })
+    }
+
+    /// Config for the backup hosts of a write-only repository: the public key
+    /// and the chunk id key of `key`, but no slots, so it lets them seal
+    /// objects and never open one.
+    pub fn export(&self, key: &MasterKey) -> io::Result<Self> {
+        let public_key = self.public_key.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only write-only repositories back up without a password",
+            )
+        })?;
+        if key.public_key() != public_key {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "public key does not belong to the master key",
+            ));
+        }
+
+        Ok(Self {
+            slots: Vec::new(),
+            public_key: Some(public_key),
+            chunk_id_key: Some(key.chunk_id_key()),
+        })
+    }
+
+    /// Keys of the repository opened with `master_key`, or only able to seal
+    /// objects without it, which write-only repositories allow.
+    pub fn repository_key(&self, master_key: Option<MasterKey>) -> io::Result<RepositoryKey> {
+        match (self.public_key, master_key) {
+            (None, Some(key)) => Ok(RepositoryKey::Master(key)),
+            (Some(public_key), Some(key)) => {
+                if key.public_key() != public_key {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "public key does not belong to the master key",
+                    ));
+                }
+                let chunk_id_key = key.chunk_id_key();
+                if self
+                    .chunk_id_key
+                    .as_ref()
+                    .is_some_and(|v| *v != chunk_id_key)
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "chunk id key does not belong to the master key",
+                    ));
+                }
+
+                Ok(RepositoryKey::WriteOnly {
+                    public_key,
+                    chunk_id_key,
+                    master_key: Some(key),
+                })
+            }
+            (Some(public_key), None) => match &self.chunk_id_key {
+                Some(chunk_id_key) => Ok(RepositoryKey::WriteOnly {
+                    public_key,
+                    chunk_id_key: chunk_id_key.clone(),
+                    master_key: None,
+                }),
+                None => Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "backing up without the password needs a config made by `key export`",
+                )),
+            },
+            (None, None) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "repository is not write-only, its password is needed",
+            )),
+        }
+    }
+
+    /// Unwraps the master key, failing with
+    /// [`PermissionDenied`](io::ErrorKind::PermissionDenied) if `password`
+    /// opens no slot.
+    pub fn unlock(&self, password: &[u8]) -> io::Result<MasterKey> {
+        self.unlock_slot(password).map(|(_, key)| key)
+    }
+
+    /// Like [`unlock`](Self::unlock), also returning the index of the slot
+    /// `password` opens.
+    pub fn unlock_slot(&self, password: &[u8]) -> io::Result<(usize, MasterKey)> {
+        for (index, slot) in self.slots.iter().enumerate() {
+            if let Some(key) = slot.unwrap(password)? {
+                return Ok((index, key));
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "wrong password",
+        ))
+    }
+
+    /// Adds a slot opening `key` with `password`.
+    pub fn add(
+        &mut self,
+        key: &MasterKey,
+        password: &[u8],
+        label: Option<String>,
+    ) -> io::Result<&KeySlot> {
+        self.slots.push(KeySlot::new(key, password, label)?);
+
+        Ok(self.slots.last().expect("slot was just added"))
+    }
+
+    /// Removes the slot with id `id`. Whoever unwrapped the master key with
+    /// it before keeps being able to open the repository; only a new master
+    /// key, which means re-encrypting every object, revokes them. The last
+    /// slot is never removed, it would leave the repository unopenable.
+    pub fn remove(&mut self, id: &str) -> io::Result<KeySlot> {
+        let index = self.position(id)?;
+        if self.slots.len() == 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key {} is the last one", id),
+            ));
+        }
+
+        Ok(self.slots.remove(index))
+    }
+
+    /// Changes the password of the slot `old_password` opens.
+    pub fn change_password(
+        &mut self,
+        old_password: &[u8],
+        password: &[u8],
+    ) -> io::Result<&KeySlot> {
+        let (index, key) = self.unlock_slot(old_password)?;
+
+        let slot = &mut self.slots[index];
+        slot.wrap(&key, password)?;
+
+        Ok(slot)
+    }
+
+    pub fn position(&self, id: &str) -> io::Result<usize> {
+        self.slots.iter().position(|v| v.id == id).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no key with id {}", id))
+        })
+    }
+}
+
+/// Additional data objects are sealed with, so one kind of object cannot be
+/// passed off as another.
+fn kind_aad(kind: ObjectKind) -> &'static [u8] {
+    match kind {
+        ObjectKind::Chunk => b"chunk",
+        ObjectKind::Snapshot => b"snapshot",
+    }
+}
+
+/// Lays out a sealed object as its version, the nonce, then the ciphertext
+/// followed by its tag.
+fn seal(key: &[u8; KEY_LEN], plaintext: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    let mut sealed = Vec::with_capacity(1 + NONCE_LEN + plaintext.len() + TAG_LEN);
+    sealed.push(SEALED_VERSION);
+    encrypt(key, plaintext, aad, &mut sealed)?;
+
+    Ok(sealed)
+}
+
+fn open(key: &[u8; KEY_LEN], sealed: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    match sealed.split_first() {
+        Some((&SEALED_VERSION, body)) => decrypt(key, body, aad),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "object is not sealed",
+        )),
+    }
+}
+
+/// Appends a random nonce and the ciphertext of `plaintext` to `buffer`.
+fn encrypt(
+    key: &[u8; KEY_LEN],
+    plaintext: &[u8],
+    aad: &[u8],
+    buffer: &mut Vec<u8>,
+) -> io::Result<()> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    let ciphertext = cipher
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| io::Error::other("encryption failed"))?;
+
+    buffer.extend_from_slice(&nonce);
+    buffer.extend_from_slice(&ciphertext);
+
+    Ok(())
+}
+
+fn decrypt(key: &[u8; KEY_LEN], body: &[u8], aad: &[u8]) -> io::Result<Vec<u8>> {
+    if body.len() < NONCE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "object is not sealed",
+        ));
+    }
+    let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "object failed authentication"))
+}
+
+/// Serializes bytes as a lowercase hex string.
+mod hex {
+    use serde::{Deserialize, Deserializer, Serializer, de};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let text: String = bytes.iter().map(|v| format!("{:02x}", v)).collect();
+        serializer.serialize_str(&text)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        if !text.bytes().all(|v| v.is_ascii_hexdigit()) {
+            return Err(de::Error::custom("invalid hex digit"));
+        }
+        if !text.len().is_multiple_of(2) {
+            return Err(de::Error::custom("odd number of hex digits"));
+        }
+
+        (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(de::Error::custom))
+            .collect()
+    }
+}
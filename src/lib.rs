@@ -1,5 +1,10 @@
 pub mod command;
+pub mod config;
+pub mod crypto;
+pub mod location;
+pub mod lock;
 pub mod metadata;
+pub mod progress;
 pub mod reader;
 pub mod storage;
 pub mod writer;
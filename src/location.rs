@@ -0,0 +1,294 @@
+use std::{
+    fmt::{self, Debug, Display},
+    io,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de};
+
+const TAG_BLOB: u8 = 1;
+const TAG_HASH: u8 = 2;
+const TAG_MIRROR: u8 = 3;
+
+/// Where a storage keeps an object, as returned by
+/// [`StoragePut::put`](crate::storage::StoragePut::put).
+///
+/// A location is a tag byte followed by the fields of its kind, with integers
+/// written as LEB128 varints, so a blob location usually takes 6 to 8 bytes.
+/// Locations written by older versions as JSON (`{"offset":..,"length":..}`)
+/// or as a hex hash are converted when read, both from snapshots, where they
+/// share the encoding of a string, and from text.
+///
+/// As text a location is the lowercase hex of its bytes.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Location(Vec<u8>);
+
+fn invalid_location<D: Display>(reason: D) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid location: {}", reason),
+    )
+}
+
+impl Location {
+    /// A byte range of an append-only file.
+    pub fn blob(offset: u64, length: u64) -> Self {
+        let mut buffer = vec![TAG_BLOB];
+        write_varint(&mut buffer, offset);
+        write_varint(&mut buffer, length);
+
+        Self(buffer)
+    }
+
+    /// An object addressed by the blake3 hash of its content.
+    pub fn hash(hash: &[u8; 32]) -> Self {
+        let mut buffer = Vec::with_capacity(33);
+        buffer.push(TAG_HASH);
+        buffer.extend_from_slice(hash);
+
+        Self(buffer)
+    }
+
+    /// An object with blake3 hash `hash` stored in several replicas.
+    pub fn mirror(hash: &[u8; 32], replicas: &[Location]) -> Self {
+        let mut buffer = vec![TAG_MIRROR];
+        buffer.extend_from_slice(hash);
+        write_varint(&mut buffer, replicas.len() as u64);
+        for replica in replicas {
+            write_varint(&mut buffer, replica.0.len() as u64);
+            buffer.extend_from_slice(&replica.0);
+        }
+
+        Self(buffer)
+    }
+
+    /// Reads a location from its binary form, converting the text forms
+    /// written by older versions.
+    pub fn from_bytes(bytes: Vec<u8>) -> io::Result<Self> {
+        match bytes.first() {
+            None | Some(&TAG_BLOB) | Some(&TAG_HASH) | Some(&TAG_MIRROR) => Ok(Self(bytes)),
+            Some(_) => {
+                let text = std::str::from_utf8(&bytes).map_err(invalid_location)?;
+                Self::from_legacy(text)
+            }
+        }
+    }
+
+    fn from_legacy(text: &str) -> io::Result<Self> {
+        if let Ok(hash) = blake3::Hash::from_hex(text) {
+            return Ok(Self::hash(hash.as_bytes()));
+        }
+
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Legacy {
+            Blob { offset: u64, length: u64 },
+            Mirror { hash: String, keys: Vec<String> },
+        }
+
+        match serde_json::from_str(text).map_err(invalid_location)? {
+            Legacy::Blob { offset, length } => Ok(Self::blob(offset, length)),
+            Legacy::Mirror { hash, keys } => {
+                let hash = blake3::Hash::from_hex(hash).map_err(invalid_location)?;
+                let replicas = keys
+                    .iter()
+                    .map(|v| Self::from_legacy(v))
+                    .collect::<io::Result<Vec<_>>>()?;
+
+                Ok(Self::mirror(hash.as_bytes(), &replicas))
+            }
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Whether this is the empty location returned by storages that do not
+    /// keep what they are given.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn to_blob(&self) -> io::Result<(u64, u64)> {
+        let mut bytes = self.fields(TAG_BLOB, "blob")?;
+        let offset = read_varint(&mut bytes)?;
+        let length = read_varint(&mut bytes)?;
+
+        Ok((offset, length))
+    }
+
+    pub fn to_hash(&self) -> io::Result<[u8; 32]> {
+        let mut bytes = self.fields(TAG_HASH, "hash")?;
+        let hash = read_hash(&mut bytes)?;
+
+        Ok(hash)
+    }
+
+    pub fn to_mirror(&self) -> io::Result<([u8; 32], Vec<Location>)> {
+        let mut bytes = self.fields(TAG_MIRROR, "mirror")?;
+        let hash = read_hash(&mut bytes)?;
+
+        let count = read_varint(&mut bytes)?;
+        let mut replicas = Vec::new();
+        for _ in 0..count {
+            let len = usize::try_from(read_varint(&mut bytes)?).map_err(invalid_location)?;
+            if bytes.len() < len {
+                return Err(invalid_location("truncated mirror"));
+            }
+            let (replica, rest) = bytes.split_at(len);
+            replicas.push(Self(replica.to_vec()));
+            bytes = rest;
+        }
+
+        Ok((hash, replicas))
+    }
+
+    fn fields(&self, tag: u8, kind: &str) -> io::Result<&[u8]> {
+        match self.0.split_first() {
+            Some((first, rest)) if *first == tag => Ok(rest),
+            _ => Err(invalid_location(format!(
+                "{} is not a {} location",
+                self, kind
+            ))),
+        }
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Debug for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Location({})", self)
+    }
+}
+
+impl FromStr for Location {
+    type Err = io::Error;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+
+        // A 64 digit hex string is an old hash key, binary locations are
+        // never 32 bytes long.
+        if text.starts_with('{') || text.len() == 64 {
+            return Self::from_legacy(text);
+        }
+
+        // Checked first, as slicing the text at a byte that is not a char
+        // boundary would panic.
+        if !text.bytes().all(|v| v.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid location: not a hex string",
+            ));
+        }
+        if !text.len().is_multiple_of(2) {
+            return Err(invalid_location("odd number of hex digits"));
+        }
+
+        let bytes = (0..text.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&text[i..i + 2], 16).map_err(invalid_location))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Self::from_bytes(bytes)
+    }
+}
+
+impl Serialize for Location {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Location {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let text = String::deserialize(deserializer)?;
+            text.parse().map_err(de::Error::custom)
+        } else {
+            // Shares its encoding with the strings older snapshots held.
+            let bytes = serde_bytes_buf(deserializer)?;
+            Self::from_bytes(bytes).map_err(de::Error::custom)
+        }
+    }
+}
+
+fn serde_bytes_buf<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    struct Visitor;
+
+    impl de::Visitor<'_> for Visitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("location bytes")
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v)
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            Ok(v.as_bytes().to_vec())
+        }
+
+        fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+            Ok(v.into_bytes())
+        }
+    }
+
+    deserializer.deserialize_byte_buf(Visitor)
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = bytes
+            .split_first()
+            .ok_or_else(|| invalid_location("truncated varint"))?;
+        *bytes = rest;
+
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(invalid_location("varint is too long"))
+}
+
+fn read_hash(bytes: &mut &[u8]) -> io::Result<[u8; 32]> {
+    if bytes.len() < 32 {
+        return Err(invalid_location("truncated hash"));
+    }
+    let (hash, rest) = bytes.split_at(32);
+    *bytes = rest;
+
+    Ok(hash.try_into().expect("hash has 32 bytes"))
+}
@@ -0,0 +1,319 @@
+use std::{
+    collections::hash_map::RandomState,
+    fmt::{self, Display},
+    hash::{BuildHasher, Hasher},
+    io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::instrument;
+
+const LOCK_EXTENSION: &str = "lock";
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Who holds a lock, as written in its file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LockInfo {
+    pub exclusive: bool,
+    pub host: String,
+    pub pid: u32,
+    /// Seconds since the Unix epoch.
+    pub created: u64,
+}
+
+impl LockInfo {
+    fn current(exclusive: bool) -> Self {
+        Self {
+            exclusive,
+            host: hostname(),
+            pid: std::process::id(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|v| v.as_secs())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Whether the process holding the lock is known to be gone. Processes
+    /// of other hosts cannot be checked and are assumed to be running.
+    pub fn is_stale(&self) -> bool {
+        self.host == hostname() && !process_exists(self.pid)
+    }
+}
+
+impl Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} lock of pid {} on {} taken at {}",
+            if self.exclusive {
+                "exclusive"
+            } else {
+                "shared"
+            },
+            self.pid,
+            self.host,
+            self.created
+        )
+    }
+}
+
+/// A lock found in a [`LockStore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockEntry {
+    Held(LockInfo),
+    /// A lock that cannot be parsed, e.g. truncated. Nothing tells whether
+    /// its holder still runs, so it conflicts with every lock until removed
+    /// with `unlock --all`.
+    Malformed(String),
+}
+
+impl Display for LockEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Held(info) => info.fmt(f),
+            Self::Malformed(reason) => write!(f, "malformed lock ({})", reason),
+        }
+    }
+}
+
+/// Where the locks of a repository are kept, next to its objects so that
+/// every process using the repository sees them.
+#[async_trait]
+pub trait LockStore: Send + Sync + fmt::Debug {
+    /// Stores lock `name` holding `buffer`, written whole or not at all.
+    async fn create(&self, name: &str, buffer: &[u8]) -> io::Result<()>;
+
+    /// Returns the name and content of every lock.
+    async fn list(&self) -> io::Result<Vec<(String, Vec<u8>)>>;
+
+    /// Removes lock `name`, succeeding if it is already gone.
+    async fn remove(&self, name: &str) -> io::Result<()>;
+
+    /// Removes lock `name` when a lock is dropped, where nothing can be
+    /// awaited.
+    fn remove_blocking(&self, name: &str) -> io::Result<()>;
+}
+
+/// Keeps locks as files in a directory.
+#[derive(Debug)]
+pub struct DirectoryLocks {
+    dir: PathBuf,
+}
+
+impl DirectoryLocks {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name).with_extension(LOCK_EXTENSION)
+    }
+}
+
+#[async_trait]
+impl LockStore for DirectoryLocks {
+    async fn create(&self, name: &str, buffer: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.dir).await?;
+
+        // Written aside and renamed, so other processes never read a
+        // partially written lock.
+        let temp_path = self.dir.join(name).with_extension(TEMP_EXTENSION);
+        fs::write(&temp_path, buffer).await?;
+        fs::rename(&temp_path, self.path(name)).await
+    }
+
+    async fn list(&self) -> io::Result<Vec<(String, Vec<u8>)>> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut locks = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|v| v != LOCK_EXTENSION) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|v| v.to_str()) else {
+                continue;
+            };
+
+            let buffer = match fs::read(&path).await {
+                Ok(v) => v,
+                // Released while listing.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            locks.push((name.to_string(), buffer));
+        }
+
+        Ok(locks)
+    }
+
+    async fn remove(&self, name: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(name)).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn remove_blocking(&self, name: &str) -> io::Result<()> {
+        match std::fs::remove_file(self.path(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A lock on a repository held across processes, kept in the
+/// [`LockStore`] of the repository. Any number of shared locks can be held
+/// at once, an exclusive lock only alone. Locks left behind by processes of
+/// this host that no longer run are removed when found; the others stay until
+/// removed with [`RepositoryLock::unlock`].
+///
+/// The lock is released when dropped.
+#[derive(Debug)]
+pub struct RepositoryLock {
+    store: Box<dyn LockStore>,
+    name: String,
+}
+
+impl RepositoryLock {
+    /// Takes a lock that only excludes exclusive locks, for commands that
+    /// read the repository.
+    pub async fn shared(store: Box<dyn LockStore>) -> io::Result<Self> {
+        Self::acquire(store, false).await
+    }
+
+    /// Takes a lock that excludes every other lock, for commands that write
+    /// the repository.
+    pub async fn exclusive(store: Box<dyn LockStore>) -> io::Result<Self> {
+        Self::acquire(store, true).await
+    }
+
+    #[instrument(err)]
+    async fn acquire(store: Box<dyn LockStore>, exclusive: bool) -> io::Result<Self> {
+        let info = LockInfo::current(exclusive);
+        let buffer = serde_json::to_vec(&info).map_err(io::Error::other)?;
+
+        let name = format!(
+            "{:016x}",
+            RandomState::new().build_hasher().finish() ^ u64::from(info.pid)
+        );
+        store.create(&name, &buffer).await?;
+
+        // Own lock is released on drop, also when another one conflicts.
+        let lock = Self { store, name };
+
+        for (other_name, other) in Self::list(&*lock.store).await? {
+            if other_name == lock.name {
+                continue;
+            }
+
+            let other = match other {
+                LockEntry::Held(v) => v,
+                LockEntry::Malformed(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ResourceBusy,
+                        format!(
+                            "repository is locked, {} ({}), remove it with `unlock --all`",
+                            other, other_name
+                        ),
+                    ));
+                }
+            };
+
+            if other.is_stale() {
+                tracing::warn!("removing stale {}", other);
+                lock.store.remove(&other_name).await?;
+                continue;
+            }
+
+            if exclusive || other.exclusive {
+                return Err(io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    format!("repository is locked, {} ({})", other, other_name),
+                ));
+            }
+        }
+
+        Ok(lock)
+    }
+
+    /// Returns the locks currently held on the repository. One that cannot
+    /// be parsed is returned as [`LockEntry::Malformed`] rather than failing
+    /// the whole list.
+    #[instrument(err)]
+    pub async fn list(store: &dyn LockStore) -> io::Result<Vec<(String, LockEntry)>> {
+        let locks = store
+            .list()
+            .await?
+            .into_iter()
+            .map(|(name, buffer)| {
+                let entry = match serde_json::from_slice(&buffer) {
+                    Ok(info) => LockEntry::Held(info),
+                    Err(e) => LockEntry::Malformed(e.to_string()),
+                };
+
+                (name, entry)
+            })
+            .collect();
+
+        Ok(locks)
+    }
+
+    /// Removes the stale locks of the repository, or every lock, malformed
+    /// ones included, when `all` is set, returning those removed. Removing
+    /// the lock of a running process lets other commands run alongside it.
+    #[instrument(err)]
+    pub async fn unlock(store: &dyn LockStore, all: bool) -> io::Result<Vec<LockEntry>> {
+        let mut removed = Vec::new();
+
+        for (name, entry) in Self::list(store).await? {
+            let stale = matches!(&entry, LockEntry::Held(info) if info.is_stale());
+            if all || stale {
+                store.remove(&name).await?;
+                removed.push(entry);
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+impl Drop for RepositoryLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.store.remove_blocking(&self.name) {
+            tracing::error!("failed to release lock {}: {}", self.name, e);
+        }
+    }
+}
+
+fn hostname() -> String {
+    #[cfg(target_os = "linux")]
+    if let Ok(v) = std::fs::read_to_string("/proc/sys/kernel/hostname") {
+        return v.trim().to_string();
+    }
+
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn process_exists(pid: u32) -> bool {
+    std::path::Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Without a way to check, every process is assumed to be running.
+#[cfg(not(target_os = "linux"))]
+fn process_exists(_pid: u32) -> bool {
+    true
+}
@@ -1,19 +1,116 @@
-use std::path::PathBuf;
+use std::{fs, io, path::PathBuf, time::SystemTime};
 
 use serde::{Deserialize, Serialize};
 
+use crate::location::Location;
+
+/// Starts every encoded snapshot, naming the layout that follows. Snapshots
+/// written before it are told apart by trying each older layout in turn.
+const SNAPSHOT_MAGIC: &[u8; 4] = b"LPS2";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Snapshot {
     pub files: Vec<File>,
     pub chunks: Vec<Chunk>,
     pub file_chunks: Vec<FileChunk>,
     pub file_symlink: Vec<FileSymlink>,
+    /// Set on checkpoints written while a backup is still running. Their
+    /// chunks are referenced by nothing else until the backup completes, so
+    /// they must be kept alive as long as the checkpoint may be resumed.
+    pub incomplete: bool,
+}
+
+/// Snapshot layout written before chunks carried a checksum.
+#[derive(Deserialize)]
+struct SnapshotV1 {
+    files: Vec<File>,
+    chunks: Vec<ChunkV0>,
+    file_chunks: Vec<FileChunk>,
+    file_symlink: Vec<FileSymlink>,
+    incomplete: bool,
+}
+
+/// Snapshot layout written before checkpoints existed.
+#[derive(Deserialize)]
+struct SnapshotV0 {
+    files: Vec<FileV0>,
+    chunks: Vec<ChunkV0>,
+    file_chunks: Vec<FileChunk>,
+    file_symlink: Vec<FileSymlink>,
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut buffer = SNAPSHOT_MAGIC.to_vec();
+        bincode::serialize_into(&mut buffer, self).map_err(io::Error::other)?;
+
+        Ok(buffer)
+    }
+
+    pub fn decode(buffer: &[u8]) -> io::Result<Self> {
+        if let Some(buffer) = buffer.strip_prefix(SNAPSHOT_MAGIC) {
+            return bincode::deserialize(buffer)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+
+        if let Ok(snapshot) = bincode::deserialize(buffer) {
+            return Ok(snapshot);
+        }
+
+        if let Ok(snapshot) = bincode::deserialize::<SnapshotV1>(buffer) {
+            return Ok(Self {
+                files: snapshot.files,
+                chunks: snapshot.chunks.into_iter().map(Chunk::from).collect(),
+                file_chunks: snapshot.file_chunks,
+                file_symlink: snapshot.file_symlink,
+                incomplete: snapshot.incomplete,
+            });
+        }
+
+        let snapshot: SnapshotV0 = bincode::deserialize(buffer)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(Self {
+            files: snapshot
+                .files
+                .into_iter()
+                .map(|v| File {
+                    path: v.path,
+                    stat: None,
+                })
+                .collect(),
+            chunks: snapshot.chunks.into_iter().map(Chunk::from).collect(),
+            file_chunks: snapshot.file_chunks,
+            file_symlink: snapshot.file_symlink,
+            incomplete: false,
+        })
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Chunk {
+    /// Id the chunk is deduplicated by, keyed in encrypted repositories.
     pub hash: [u8; 32],
-    pub location: String,
+    pub location: Location,
+    /// Plain blake3 hash of the content, checked on restore. Chunks of
+    /// snapshots written before it was recorded have none.
+    pub checksum: Option<[u8; 32]>,
+}
+
+#[derive(Deserialize)]
+struct ChunkV0 {
+    hash: [u8; 32],
+    location: Location,
+}
+
+impl From<ChunkV0> for Chunk {
+    fn from(chunk: ChunkV0) -> Self {
+        Self {
+            hash: chunk.hash,
+            location: chunk.location,
+            checksum: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +123,69 @@ pub struct FileSymlink {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct File {
     pub path: PathBuf,
+    /// The file as it was scanned, or `None` for streams.
+    pub stat: Option<FileStat>,
+}
+
+#[derive(Deserialize)]
+struct FileV0 {
+    path: PathBuf,
+}
+
+/// Size, modification time and identity of a file when it was scanned. A
+/// checkpoint only covers a file that still has the same stat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStat {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub id: Option<FileId>,
+}
+
+impl FileStat {
+    pub fn from_metadata(meta: &fs::Metadata) -> Self {
+        Self {
+            size: meta.len(),
+            modified: meta.modified().ok(),
+            id: FileId::from_metadata(meta),
+        }
+    }
+}
+
+/// Device and inode of a file, shared by all of its hard links.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub struct FileId {
+    pub volume_id: u64,
+    pub file_index: u64,
+}
+
+impl FileId {
+    #[cfg(not(any(unix, windows)))]
+    pub fn from_metadata(_meta: &fs::Metadata) -> Option<FileId> {
+        None
+    }
+
+    #[cfg(unix)]
+    pub fn from_metadata(meta: &fs::Metadata) -> Option<FileId> {
+        use std::os::unix::fs::MetadataExt;
+        Some(FileId {
+            volume_id: meta.dev(),
+            file_index: meta.ino(),
+        })
+    }
+
+    #[cfg(all(windows, feature = "experimental"))]
+    pub fn from_metadata(meta: &fs::Metadata) -> Option<FileId> {
+        use std::os::windows::fs::MetadataExt;
+        Some(FileId {
+            volume_id: meta.volume_serial_number()?.into(),
+            file_index: meta.file_index()?,
+        })
+    }
+
+    #[cfg(all(windows, not(feature = "experimental")))]
+    pub fn from_metadata(_meta: &fs::Metadata) -> Option<FileId> {
+        None
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::location::Location;
+
+pub type ProgressSender = mpsc::UnboundedSender<ProgressEvent>;
+pub type ProgressReceiver = mpsc::UnboundedReceiver<ProgressEvent>;
+
+pub fn channel() -> (ProgressSender, ProgressReceiver) {
+    mpsc::unbounded_channel()
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// A file was found and will be chunked. `size` is unknown for streams.
+    FileScanned { path: PathBuf, size: Option<u64> },
+    /// Discovery is done, chunking is about to start.
+    ScanFinished { files: u64, bytes: u64 },
+    /// A chunk starting in `path` was produced by the chunker.
+    ChunkProcessed {
+        path: PathBuf,
+        length: u64,
+        reused: bool,
+    },
+    /// An object was written to the storage.
+    Uploaded { length: u64 },
+    /// An incomplete snapshot was stored; pass `key` as the resume key if
+    /// the backup gets interrupted.
+    Checkpoint { key: Location },
+    /// The chunk map at `path` could not be read and was dropped, so chunks
+    /// it listed are stored again.
+    ChunkMapDropped { path: PathBuf, reason: String },
+    /// A snapshot was loaded and is about to be restored.
+    RestoreStarted { files: u64, bytes: u64 },
+    /// A slice of `path` was written to the destination.
+    Written { path: PathBuf, length: u64 },
+}
+
+/// Running totals folded from a stream of [`ProgressEvent`].
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Progress {
+    pub files_total: u64,
+    pub bytes_total: u64,
+    pub files_scanned: u64,
+    pub bytes_chunked: u64,
+    pub chunks_new: u64,
+    pub chunks_reused: u64,
+    pub bytes_new: u64,
+    pub bytes_uploaded: u64,
+    pub bytes_written: u64,
+    pub current_path: Option<PathBuf>,
+}
+
+impl Progress {
+    pub fn update(&mut self, event: &ProgressEvent) {
+        match event {
+            ProgressEvent::FileScanned { size, .. } => {
+                self.files_scanned += 1;
+                self.bytes_total += size.unwrap_or(0);
+            }
+            ProgressEvent::ScanFinished { files, bytes } => {
+                self.files_total = *files;
+                self.bytes_total = *bytes;
+            }
+            ProgressEvent::ChunkProcessed {
+                path,
+                length,
+                reused,
+            } => {
+                self.bytes_chunked += length;
+                if *reused {
+                    self.chunks_reused += 1;
+                } else {
+                    self.chunks_new += 1;
+                    self.bytes_new += length;
+                }
+                self.current_path = Some(path.clone());
+            }
+            ProgressEvent::Uploaded { length } => {
+                self.bytes_uploaded += length;
+            }
+            ProgressEvent::Checkpoint { .. } | ProgressEvent::ChunkMapDropped { .. } => {}
+            ProgressEvent::RestoreStarted { files, bytes } => {
+                self.files_total = *files;
+                self.bytes_total = *bytes;
+            }
+            ProgressEvent::Written { path, length } => {
+                self.bytes_written += length;
+                self.current_path = Some(path.clone());
+            }
+        }
+    }
+}
+
+pub(crate) fn report(sender: &Option<ProgressSender>, event: ProgressEvent) {
+    if let Some(sender) = sender {
+        let _ = sender.send(event);
+    }
+}
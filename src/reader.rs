@@ -66,21 +66,34 @@ impl FileRegistry {
         Ok(Self { entries })
     }
 
+    #[instrument(level = "trace")]
+    pub fn unbounded(path: PathBuf) -> Self {
+        let entry = EntryFileRegistry {
+            path,
+            length: u64::MAX,
+            global_offset: 0,
+        };
+
+        Self {
+            entries: vec![entry],
+        }
+    }
+
     #[instrument(level = "trace", skip(self))]
     pub fn resolve_chunk(&self, global_start: u64, length: u32) -> Vec<ChunkSource> {
-        let global_end = global_start + length as u64;
+        let global_end = global_start.saturating_add(length as u64);
         let mut mappings = Vec::new();
 
-        let start_index = self
-            .entries
-            .partition_point(|entry| (entry.global_offset + entry.length) <= global_start);
+        let start_index = self.entries.partition_point(|entry| {
+            entry.global_offset.saturating_add(entry.length) <= global_start
+        });
 
         for entry in self.entries.iter().skip(start_index) {
             if entry.global_offset >= global_end {
                 break;
             }
 
-            let file_end_global = entry.global_offset + entry.length;
+            let file_end_global = entry.global_offset.saturating_add(entry.length);
 
             let overlap_start_global = std::cmp::max(global_start, entry.global_offset);
             let overlap_end_global = std::cmp::min(global_end, file_end_global);
@@ -104,7 +117,7 @@ impl FileRegistry {
 
 pub struct GlobalStream {
     paths: VecDeque<PathBuf>,
-    current_file: Option<File>,
+    current_file: Option<Box<dyn Read + Send>>,
 }
 
 impl GlobalStream {
@@ -117,6 +130,14 @@ impl GlobalStream {
             current_file: None,
         }
     }
+
+    #[instrument(level = "trace", skip(reader))]
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Self {
+        Self {
+            paths: VecDeque::new(),
+            current_file: Some(Box::new(reader)),
+        }
+    }
 }
 
 impl Read for GlobalStream {
@@ -137,7 +158,7 @@ impl Read for GlobalStream {
             match self.paths.pop_front() {
                 Some(path) => {
                     let file = File::open(path)?;
-                    self.current_file = Some(file);
+                    self.current_file = Some(Box::new(file));
                 }
                 None => return Ok(0),
             }
@@ -152,6 +173,16 @@ pub struct ChunkerConfig {
     pub max_size: u32,
 }
 
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 8 * 1024,
+            avg_size: 16 * 1024,
+            max_size: 64 * 1024,
+        }
+    }
+}
+
 pub struct Chunker {
     registry: Arc<FileRegistry>,
     cdc_iter: StreamCDC<GlobalStream>,
@@ -170,6 +201,23 @@ impl Chunker {
             cdc_iter: cdc,
         })
     }
+
+    #[instrument(level = "trace", skip(reader))]
+    pub fn from_reader<R: Read + Send + 'static>(
+        path: PathBuf,
+        reader: R,
+        config: ChunkerConfig,
+    ) -> Self {
+        let registry = Arc::new(FileRegistry::unbounded(path));
+
+        let stream = GlobalStream::from_reader(reader);
+        let cdc = StreamCDC::new(stream, config.min_size, config.avg_size, config.max_size);
+
+        Self {
+            registry,
+            cdc_iter: cdc,
+        }
+    }
 }
 
 impl Iterator for Chunker {
@@ -184,7 +232,7 @@ impl Iterator for Chunker {
 
         let sources = self
             .registry
-            .resolve_chunk(cdc_chunk.offset as u64, cdc_chunk.length as u32);
+            .resolve_chunk(cdc_chunk.offset, cdc_chunk.length as u32);
 
         let reader = Cursor::new(cdc_chunk.data);
         let reader = Box::new(reader);
@@ -280,7 +328,7 @@ where
         let delta = (new_position as i128 - self.position as i128) as i64;
         self.position = new_position;
 
-        Pin::new(&mut self.inner).start_seek(io::SeekFrom::Current(delta as i64))
+        Pin::new(&mut self.inner).start_seek(io::SeekFrom::Current(delta))
     }
 
     #[instrument(level = "trace", skip(self, cx), ret)]
@@ -2,28 +2,53 @@ use std::{
     fmt::Debug,
     io::{self, SeekFrom},
     path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
-use tokio::{fs, io::AsyncSeekExt, sync::RwLock};
+use bytes::Bytes;
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::RwLock,
+};
 use tracing::instrument;
 
 use crate::{
+    location::Location,
     reader::{self, SliceAsyncReader},
     storage,
 };
 
-#[derive(Serialize, Deserialize, Debug)]
-struct BlobEntry {
-    offset: u64,
-    length: u64,
+/// Starts every record, followed by the kind of the object, its little endian
+/// length and its blake3 hash.
+const RECORD_MAGIC: &[u8; 4] = b"LPR2";
+const RECORD_HEADER_LEN: u64 = 4 + 1 + 8 + 32;
+
+const KIND_CHUNK: u8 = 1;
+const KIND_SNAPSHOT: u8 = 2;
+
+/// A record found by [`BlobFileStorage::records`].
+#[derive(Debug, Clone)]
+pub struct BlobRecord {
+    pub location: Location,
+    pub kind: storage::ObjectKind,
 }
 
+/// Appends every object to a single file as a record framed by its kind,
+/// length and hash, so the objects of a damaged file can still be told apart
+/// from garbage and its snapshots found without index files. Keys point at
+/// the object itself, past the header, which keeps keys of files written
+/// before records were framed valid.
+///
+/// A record that fails halfway is cut off the end of the file before the
+/// error is returned.
 #[derive(Debug)]
 pub struct BlobFileStorage<const WRITE: bool> {
     file_path: PathBuf,
     lock: RwLock<()>,
+    sync_policy: storage::SyncPolicy,
+    dirty: AtomicBool,
 }
 
 impl<const WRITE: bool> BlobFileStorage<WRITE> {
@@ -39,6 +64,7 @@ impl<const WRITE: bool> BlobFileStorage<WRITE> {
             fs::OpenOptions::new()
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(&file_path)
                 .await?;
         }
@@ -46,51 +72,323 @@ impl<const WRITE: bool> BlobFileStorage<WRITE> {
         Ok(Self {
             file_path,
             lock: RwLock::new(()),
+            sync_policy: storage::SyncPolicy::default(),
+            dirty: AtomicBool::new(false),
         })
     }
+
+    pub fn sync_policy(mut self, policy: storage::SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Scans the whole file for intact records, in the order they were
+    /// written. Bytes that do not form a record with a matching hash, such as
+    /// data written before records were framed, are skipped.
+    #[instrument(err)]
+    pub async fn records(&self) -> io::Result<Vec<BlobRecord>> {
+        let _guard = self.lock.read().await;
+
+        let mut file = fs::File::open(&self.file_path).await?;
+        let size = file.metadata().await?.len();
+
+        let mut records = Vec::new();
+        let mut position = 0;
+
+        while position < size {
+            match read_record(&mut file, position, size).await? {
+                Some((record, next)) => {
+                    records.push(record);
+                    position = next;
+                }
+                None => position = find_magic(&mut file, position + 1, size).await?,
+            }
+        }
+
+        Ok(records)
+    }
 }
 
 #[async_trait]
 impl<const WRITE: bool> storage::StorageGet for BlobFileStorage<WRITE> {
     #[instrument(err)]
-    async fn get(&self, key: &str) -> io::Result<reader::StreamReadSeeker> {
-        let entry: BlobEntry = serde_json::from_str(key).map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidInput,
-                format!("Invalid blob key: {}", e),
-            )
-        })?;
+    async fn get(
+        &self,
+        key: &Location,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let (offset, length) = key.to_blob()?;
+
+        let _guard = self.lock.read().await;
+
+        let mut file = fs::File::open(&self.file_path).await?;
+
+        file.seek(SeekFrom::Start(offset)).await?;
+        let limited_reader = SliceAsyncReader::new(file, length);
+
+        Ok(Box::new(limited_reader))
+    }
+
+    #[instrument(err)]
+    async fn get_range(
+        &self,
+        key: &Location,
+        offset: u64,
+        len: u64,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let (object_offset, length) = key.to_blob()?;
+        let start = object_offset + storage::check_range(length, offset, len)?;
 
         let _guard = self.lock.read().await;
 
         let mut file = fs::File::open(&self.file_path).await?;
 
-        file.seek(SeekFrom::Start(entry.offset)).await?;
-        let limited_reader = SliceAsyncReader::new(file, entry.length);
+        file.seek(SeekFrom::Start(start)).await?;
+        let limited_reader = SliceAsyncReader::new(file, len);
 
         Ok(Box::new(limited_reader))
     }
 }
 
-#[async_trait]
-impl storage::StoragePut for BlobFileStorage<true> {
-    #[instrument(skip(reader), ret, err)]
-    async fn put(&self, mut reader: reader::StreamReadSeeker, _len: u64) -> io::Result<String> {
+impl BlobFileStorage<true> {
+    /// Appends a record for every reader with a single open of the file. If
+    /// one fails, the records already written by this call are cut off too.
+    async fn append<R, I>(&self, readers: I, kind: storage::ObjectKind) -> io::Result<Vec<Location>>
+    where
+        R: AsyncRead + Unpin,
+        I: IntoIterator<Item = R>,
+    {
+        let kind = match kind {
+            storage::ObjectKind::Chunk => KIND_CHUNK,
+            storage::ObjectKind::Snapshot => KIND_SNAPSHOT,
+        };
+
         let _guard = self.lock.write().await;
 
         let mut file = fs::OpenOptions::new()
             .create(true)
-            .append(true)
+            .write(true)
+            .truncate(false)
             .open(&self.file_path)
             .await?;
 
-        let offset = file.metadata().await?.len();
+        let start = file.seek(SeekFrom::End(0)).await?;
+
+        let mut keys = Vec::new();
+        for reader in readers {
+            let result = async {
+                let offset = file.seek(SeekFrom::End(0)).await?;
+                let length = write_record(&mut file, kind, reader).await?;
+
+                Ok(Location::blob(offset + RECORD_HEADER_LEN, length))
+            }
+            .await;
+
+            match result {
+                Ok(key) => keys.push(key),
+                Err(e) => {
+                    if let Err(e) = file.set_len(start).await {
+                        tracing::error!("failed to truncate partial record: {}", e);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        if self.sync_policy == storage::SyncPolicy::Always {
+            file.sync_data().await?;
+        } else {
+            self.dirty.store(true, Ordering::Release);
+        }
+
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl storage::StoragePut for BlobFileStorage<true> {
+    #[instrument(skip(reader), ret, err)]
+    async fn put(
+        &self,
+        reader: reader::StreamReadSeeker,
+        _len: u64,
+        kind: storage::ObjectKind,
+    ) -> io::Result<Location> {
+        let mut keys = self.append(std::iter::once(reader), kind).await?;
 
-        let length = tokio::io::copy(&mut reader, &mut file).await?;
+        Ok(keys.remove(0))
+    }
+
+    #[instrument(skip(self, objects), err)]
+    async fn put_batch(
+        &self,
+        objects: &[Bytes],
+        kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Location>> {
+        self.append(objects.iter().map(Bytes::as_ref), kind).await
+    }
 
-        let entry = BlobEntry { offset, length };
-        let key = serde_json::to_string(&entry).map_err(|e| io::Error::other(e))?;
+    #[instrument(err)]
+    async fn flush(&self) -> io::Result<()> {
+        if self.sync_policy == storage::SyncPolicy::Never
+            || !self.dirty.swap(false, Ordering::AcqRel)
+        {
+            return Ok(());
+        }
 
-        Ok(key)
+        let _guard = self.lock.read().await;
+
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(&self.file_path)
+            .await?;
+
+        let result = file.sync_data().await;
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Release);
+        }
+
+        result
+    }
+}
+
+/// Writes a placeholder header and the object, then fills in the header once
+/// the length and hash are known. An interrupted write leaves a header
+/// without magic, which recovery skips.
+async fn write_record<R: AsyncRead + Unpin>(
+    file: &mut fs::File,
+    kind: u8,
+    mut reader: R,
+) -> io::Result<u64> {
+    let start = file.stream_position().await?;
+    file.write_all(&[0u8; RECORD_HEADER_LEN as usize]).await?;
+
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut length = 0u64;
+
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..n]);
+        file.write_all(&buffer[..n]).await?;
+        length += n as u64;
+    }
+
+    let mut header = Vec::with_capacity(RECORD_HEADER_LEN as usize);
+    header.extend_from_slice(RECORD_MAGIC);
+    header.push(kind);
+    header.extend_from_slice(&length.to_le_bytes());
+    header.extend_from_slice(hasher.finalize().as_bytes());
+
+    file.seek(SeekFrom::Start(start)).await?;
+    file.write_all(&header).await?;
+    file.flush().await?;
+
+    Ok(length)
+}
+
+/// Reads the record starting at `position`, returning it with the position
+/// following it, or `None` when there is no intact record there.
+async fn read_record(
+    file: &mut fs::File,
+    position: u64,
+    size: u64,
+) -> io::Result<Option<(BlobRecord, u64)>> {
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    let available = (size - position).min(RECORD_HEADER_LEN) as usize;
+    file.seek(SeekFrom::Start(position)).await?;
+    file.read_exact(&mut header[..available]).await?;
+
+    if available as u64 != RECORD_HEADER_LEN || &header[..4] != RECORD_MAGIC {
+        return Ok(None);
+    }
+
+    let kind = match header[4] {
+        KIND_CHUNK => storage::ObjectKind::Chunk,
+        KIND_SNAPSHOT => storage::ObjectKind::Snapshot,
+        _ => return Ok(None),
+    };
+    let length = u64::from_le_bytes(header[5..13].try_into().expect("length has 8 bytes"));
+    let hash: [u8; 32] = header[13..45].try_into().expect("hash has 32 bytes");
+
+    let start = position + RECORD_HEADER_LEN;
+    let end = match start.checked_add(length) {
+        Some(v) if v <= size => v,
+        _ => return Ok(None),
+    };
+
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut reader = SliceAsyncReader::new(&mut *file, length);
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+
+    if hasher.finalize() != hash {
+        return Ok(None);
+    }
+
+    let record = BlobRecord {
+        location: Location::blob(start, length),
+        kind,
+    };
+
+    Ok(Some((record, end)))
+}
+
+/// Finds the next position at or after `position` that starts with a record
+/// magic, or `size` when there is none.
+async fn find_magic(file: &mut fs::File, position: u64, size: u64) -> io::Result<u64> {
+    file.seek(SeekFrom::Start(position)).await?;
+
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut buffer_start = position;
+    let mut kept = 0;
+
+    loop {
+        let n = file.read(&mut buffer[kept..]).await?;
+        if n == 0 {
+            return Ok(size);
+        }
+        let filled = kept + n;
+
+        let found = buffer[..filled].windows(4).position(|v| v == RECORD_MAGIC);
+        if let Some(index) = found {
+            return Ok(buffer_start + index as u64);
+        }
+
+        // Keep the last bytes in case a magic spans two reads.
+        kept = filled.min(3);
+        buffer.copy_within(filled - kept..filled, 0);
+        buffer_start += (filled - kept) as u64;
+    }
+}
+
+#[async_trait]
+impl<const WRITE: bool> storage::StorageList for BlobFileStorage<WRITE> {
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        let records = self.records().await?;
+
+        Ok(records.into_iter().map(|v| v.location).collect())
+    }
+}
+
+#[async_trait]
+impl storage::StorageDelete for BlobFileStorage<true> {
+    async fn delete(&self, _key: &Location) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "blob storage is append-only",
+        ))
     }
 }
@@ -0,0 +1,339 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    io::{self, Cursor},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::{fs, io::AsyncReadExt, sync::Mutex};
+use tracing::instrument;
+
+use crate::{location::Location, reader, storage};
+
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Every cached file starts with the blake3 hash of the object it holds.
+const HASH_LEN: usize = 32;
+
+#[derive(Debug, Default)]
+struct CacheState {
+    /// Size and last use of every cached file, by file name.
+    entries: HashMap<String, (u64, u64)>,
+    /// File names by last use, oldest first.
+    by_use: BTreeMap<u64, String>,
+    total_size: u64,
+    clock: u64,
+}
+
+impl CacheState {
+    /// Marks `name` as the most recently used file, returning whether it is
+    /// cached.
+    fn touch(&mut self, name: &str) -> bool {
+        self.clock += 1;
+        let clock = self.clock;
+
+        match self.entries.get_mut(name) {
+            Some((_, last_used)) => {
+                self.by_use.remove(last_used);
+                *last_used = clock;
+                self.by_use.insert(clock, name.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, name: String, size: u64) {
+        self.remove(&name);
+
+        self.clock += 1;
+        self.by_use.insert(self.clock, name.clone());
+        self.entries.insert(name, (size, self.clock));
+        self.total_size += size;
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        match self.entries.remove(name) {
+            Some((size, last_used)) => {
+                self.by_use.remove(&last_used);
+                self.total_size -= size;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Keeps a copy of recently read and written objects in a local directory of
+/// at most `max_size` bytes, so repeated reads of snapshots and hot chunks do
+/// not go through a slow inner storage. Writes go to the inner storage first
+/// and are cached once it has accepted them.
+///
+/// Cached files are named after the blake3 hash of their key and the least
+/// recently used ones are evicted first. Across restarts the order falls back
+/// to the time the files were written. Each file records the hash of its
+/// content and is dropped instead of served when it no longer matches.
+///
+/// Keys of content-addressed storages name the same object in every
+/// repository, but offsets into a blob file do not: repositories sharing a
+/// cache directory must each set their own [`namespace`](Self::namespace).
+#[derive(Debug)]
+pub struct CachedStorage<S> {
+    inner: S,
+    root: PathBuf,
+    max_size: u64,
+    namespace: String,
+    state: Mutex<CacheState>,
+}
+
+impl<S> CachedStorage<S> {
+    #[instrument(skip(inner), err)]
+    pub async fn new<P: Into<PathBuf> + Debug>(
+        inner: S,
+        path: P,
+        max_size: u64,
+    ) -> io::Result<Self> {
+        let root = path.into();
+        fs::create_dir_all(&root).await?;
+
+        let mut files = Vec::new();
+        let mut dir = fs::read_dir(&root).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|v| v == TEMP_EXTENSION) {
+                fs::remove_file(path).await?;
+                continue;
+            }
+
+            let metadata = entry.metadata().await?;
+            if let Ok(name) = entry.file_name().into_string()
+                && metadata.is_file()
+            {
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                files.push((modified, name, metadata.len()));
+            }
+        }
+        files.sort();
+
+        let cache = Self {
+            inner,
+            root,
+            max_size,
+            namespace: String::new(),
+            state: Mutex::new(CacheState::default()),
+        };
+
+        {
+            let mut state = cache.state.lock().await;
+            for (_, name, size) in files {
+                state.insert(name, size);
+            }
+            cache.evict(&mut state).await?;
+        }
+
+        Ok(cache)
+    }
+
+    /// Separates the objects of this storage from those of other storages
+    /// cached in the same directory, e.g. with the repository name.
+    pub fn namespace<N: Into<String>>(mut self, namespace: N) -> Self {
+        self.namespace = namespace.into();
+        self
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn file_name(&self, key: &Location) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&(self.namespace.len() as u64).to_le_bytes());
+        hasher.update(self.namespace.as_bytes());
+        hasher.update(key.as_bytes());
+
+        hasher.finalize().to_hex().to_string()
+    }
+
+    async fn insert(&self, key: &Location, buffer: &[u8]) -> io::Result<()> {
+        let size = (HASH_LEN + buffer.len()) as u64;
+        if size > self.max_size {
+            return Ok(());
+        }
+
+        let name = self.file_name(key);
+        let path = self.root.join(&name);
+        let temp_path = path.with_extension(TEMP_EXTENSION);
+
+        let mut file = Vec::with_capacity(HASH_LEN + buffer.len());
+        file.extend_from_slice(blake3::hash(buffer).as_bytes());
+        file.extend_from_slice(buffer);
+
+        fs::write(&temp_path, file).await?;
+        fs::rename(&temp_path, &path).await?;
+
+        let mut state = self.state.lock().await;
+        state.insert(name, size);
+
+        self.evict(&mut state).await
+    }
+
+    /// Reads the cached copy of `name` without holding the state lock, so
+    /// hits are served concurrently. Returns `None` when it is missing, e.g.
+    /// evicted meanwhile, or does not match the hash it was stored with.
+    async fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut buffer = match fs::read(self.root.join(name)).await {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.state.lock().await.remove(name);
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let valid = buffer.len() >= HASH_LEN
+            && blake3::hash(&buffer[HASH_LEN..]).as_bytes()[..] == buffer[..HASH_LEN];
+        if !valid {
+            tracing::warn!("dropping corrupt cache file {}", name);
+            self.state.lock().await.remove(name);
+            remove_file(&self.root.join(name)).await?;
+            return Ok(None);
+        }
+
+        buffer.drain(..HASH_LEN);
+        Ok(Some(buffer))
+    }
+
+    /// Drops the cached copy of `key`, if any. A copy evicted meanwhile is
+    /// already gone.
+    async fn remove(&self, key: &Location) -> io::Result<()> {
+        let name = self.file_name(key);
+
+        if self.state.lock().await.remove(&name) {
+            remove_file(&self.root.join(name)).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn evict(&self, state: &mut CacheState) -> io::Result<()> {
+        while state.total_size > self.max_size {
+            let Some((_, name)) = state.by_use.first_key_value() else {
+                break;
+            };
+            let name = name.clone();
+
+            state.remove(&name);
+            remove_file(&self.root.join(&name)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn remove_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageGet> storage::StorageGet for CachedStorage<S> {
+    #[instrument(skip(self), err)]
+    async fn get(
+        &self,
+        key: &Location,
+        kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let name = self.file_name(key);
+
+        let cached = self.state.lock().await.touch(&name);
+        if cached && let Some(buffer) = self.read(&name).await? {
+            return Ok(Box::new(Cursor::new(buffer)));
+        }
+
+        let mut buffer = Vec::new();
+        self.inner
+            .get(key, kind)
+            .await?
+            .read_to_end(&mut buffer)
+            .await?;
+
+        if let Err(e) = self.insert(key, &buffer).await {
+            tracing::warn!("failed to cache object {}: {}", key, e);
+        }
+
+        Ok(Box::new(Cursor::new(buffer)))
+    }
+}
+
+#[async_trait]
+impl<S: storage::StoragePut> storage::StoragePut for CachedStorage<S> {
+    #[instrument(skip(self, reader), ret, err)]
+    async fn put(
+        &self,
+        mut reader: reader::StreamReadSeeker,
+        len: u64,
+        kind: storage::ObjectKind,
+    ) -> io::Result<Location> {
+        let mut buffer = storage::object_buffer(len);
+        reader.read_to_end(&mut buffer).await?;
+
+        let buffer: Arc<[u8]> = buffer.into();
+        let key = self
+            .inner
+            .put(Box::new(Cursor::new(buffer.clone())), len, kind)
+            .await?;
+
+        if !key.is_empty()
+            && let Err(e) = self.insert(&key, &buffer).await
+        {
+            tracing::warn!("failed to cache object {}: {}", key, e);
+        }
+
+        Ok(key)
+    }
+
+    #[instrument(skip(self, objects), err)]
+    async fn put_batch(
+        &self,
+        objects: &[Bytes],
+        kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Location>> {
+        let keys = self.inner.put_batch(objects, kind).await?;
+
+        for (key, object) in keys.iter().zip(objects) {
+            if !key.is_empty()
+                && let Err(e) = self.insert(key, object).await
+            {
+                tracing::warn!("failed to cache object {}: {}", key, e);
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageList> storage::StorageList for CachedStorage<S> {
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        self.inner.list().await
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageDelete> storage::StorageDelete for CachedStorage<S> {
+    #[instrument(skip(self), err)]
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        self.remove(key).await?;
+        self.inner.delete(key).await
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    fmt::Debug,
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+use tracing::instrument;
+
+use crate::{location::Location, reader, storage};
+
+const TEMP_DIR: &str = "tmp";
+
+/// Stores every object in its own file named after its blake3 hash, fanned
+/// out as `ab/cd/abcd...`. Storing the same content twice yields the same key
+/// and a single file.
+#[derive(Debug)]
+pub struct DirectoryStorage<const WRITE: bool> {
+    root: PathBuf,
+    temp_counter: AtomicU64,
+}
+
+impl<const WRITE: bool> DirectoryStorage<WRITE> {
+    #[instrument(err)]
+    pub async fn new<P: Into<PathBuf> + Debug>(path: P) -> io::Result<Self> {
+        let root = path.into();
+
+        if WRITE {
+            fs::create_dir_all(root.join(TEMP_DIR)).await?;
+        }
+
+        Ok(Self {
+            root,
+            temp_counter: AtomicU64::new(0),
+        })
+    }
+
+    fn object_path(&self, key: &Location) -> io::Result<PathBuf> {
+        let name = blake3::Hash::from_bytes(key.to_hash()?).to_hex();
+
+        Ok(self
+            .root
+            .join(&name[0..2])
+            .join(&name[2..4])
+            .join(name.as_str()))
+    }
+}
+
+#[async_trait]
+impl<const WRITE: bool> storage::StorageGet for DirectoryStorage<WRITE> {
+    #[instrument(err)]
+    async fn get(
+        &self,
+        key: &Location,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let file = fs::File::open(self.object_path(key)?).await?;
+
+        Ok(Box::new(file))
+    }
+}
+
+#[async_trait]
+impl storage::StoragePut for DirectoryStorage<true> {
+    #[instrument(skip(reader), ret, err)]
+    async fn put(
+        &self,
+        reader: reader::StreamReadSeeker,
+        _len: u64,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<Location> {
+        let temp_path = {
+            let counter = self.temp_counter.fetch_add(1, Ordering::Relaxed);
+            let name = format!("{}-{}", std::process::id(), counter);
+
+            self.root.join(TEMP_DIR).join(name)
+        };
+
+        let hash = match write_temp(&temp_path, reader).await {
+            Ok(v) => v,
+            Err(e) => {
+                let _ = fs::remove_file(&temp_path).await;
+                return Err(e);
+            }
+        };
+
+        let key = Location::hash(hash.as_bytes());
+        let object_path = self.object_path(&key)?;
+
+        if fs::try_exists(&object_path).await? {
+            fs::remove_file(&temp_path).await?;
+            return Ok(key);
+        }
+
+        if let Some(parent) = object_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&temp_path, &object_path).await?;
+
+        Ok(key)
+    }
+}
+
+#[async_trait]
+impl<const WRITE: bool> storage::StorageList for DirectoryStorage<WRITE> {
+    #[instrument(err)]
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        let mut keys = Vec::new();
+
+        let mut stack = vec![(self.root.clone(), 0)];
+        while let Some((dir, depth)) = stack.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(v) => v,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                let name = match entry.file_name().into_string() {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+
+                match depth {
+                    0 | 1 if name.len() == 2 => stack.push((entry.path(), depth + 1)),
+                    2 => {
+                        if let Ok(hash) = blake3::Hash::from_hex(&name) {
+                            keys.push(Location::hash(hash.as_bytes()));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl storage::StorageDelete for DirectoryStorage<true> {
+    #[instrument(err)]
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        fs::remove_file(self.object_path(key)?).await
+    }
+}
+
+async fn write_temp(
+    temp_path: &Path,
+    mut reader: reader::StreamReadSeeker,
+) -> io::Result<blake3::Hash> {
+    let mut file = fs::File::create(temp_path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..n]);
+        file.write_all(&buffer[..n]).await?;
+    }
+
+    file.sync_all().await?;
+
+    Ok(hasher.finalize())
+}
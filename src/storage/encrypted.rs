@@ -0,0 +1,111 @@
+use std::io::{self, Cursor};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::AsyncReadExt;
+use tracing::instrument;
+
+use crate::{crypto::RepositoryKey, location::Location, reader, storage};
+
+/// Seals every object with the repository key before handing it to the
+/// wrapped storage and opens it again when read, so the wrapped storage only
+/// ever sees ciphertext. Objects are sealed together with their kind, so a
+/// chunk read as a snapshot fails to open. Ranges are read by opening the
+/// whole object.
+#[derive(Debug)]
+pub struct EncryptedStorage<S> {
+    inner: S,
+    key: RepositoryKey,
+}
+
+impl<S> EncryptedStorage<S> {
+    pub fn new(inner: S, key: RepositoryKey) -> Self {
+        Self { inner, key }
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageGet> storage::StorageGet for EncryptedStorage<S> {
+    #[instrument(skip(self), err)]
+    async fn get(
+        &self,
+        key: &Location,
+        kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let mut buffer = Vec::new();
+        self.inner
+            .get(key, kind)
+            .await?
+            .read_to_end(&mut buffer)
+            .await?;
+
+        Ok(Box::new(Cursor::new(self.key.open(&buffer, kind)?)))
+    }
+
+    #[instrument(skip(self), err)]
+    async fn get_batch(
+        &self,
+        keys: &[Location],
+        kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        self.inner
+            .get_batch(keys, kind)
+            .await?
+            .iter()
+            .map(|v| self.key.open(v, kind))
+            .collect()
+    }
+}
+
+#[async_trait]
+impl<S: storage::StoragePut> storage::StoragePut for EncryptedStorage<S> {
+    #[instrument(skip(self, reader), err)]
+    async fn put(
+        &self,
+        mut reader: reader::StreamReadSeeker,
+        len: u64,
+        kind: storage::ObjectKind,
+    ) -> io::Result<Location> {
+        let mut buffer = storage::object_buffer(len);
+        reader.read_to_end(&mut buffer).await?;
+
+        let sealed = self.key.seal(&buffer, kind)?;
+        let len = sealed.len() as u64;
+
+        self.inner
+            .put(Box::new(Cursor::new(sealed)), len, kind)
+            .await
+    }
+
+    #[instrument(skip(self, objects), err)]
+    async fn put_batch(
+        &self,
+        objects: &[Bytes],
+        kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Location>> {
+        let sealed = objects
+            .iter()
+            .map(|v| self.key.seal(v, kind).map(Bytes::from))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        self.inner.put_batch(&sealed, kind).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.inner.flush().await
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageList> storage::StorageList for EncryptedStorage<S> {
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        self.inner.list().await
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageDelete> storage::StorageDelete for EncryptedStorage<S> {
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        self.inner.delete(key).await
+    }
+}
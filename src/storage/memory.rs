@@ -0,0 +1,113 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+    sync::{Arc, RwLock},
+};
+
+use async_trait::async_trait;
+use tokio::io::AsyncReadExt;
+use tracing::instrument;
+
+use crate::{location::Location, reader, storage};
+
+type Objects = HashMap<[u8; 32], Arc<[u8]>>;
+
+/// Keeps every object in memory under its blake3 hash. Clones share the same
+/// objects, so a clone handed to a command can be inspected afterwards.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryStorage {
+    objects: Arc<RwLock<Objects>>,
+}
+
+impl InMemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.read().values().map(|v| v.len() as u64).sum()
+    }
+
+    pub fn keys(&self) -> Vec<Location> {
+        self.read().keys().map(Location::hash).collect()
+    }
+
+    pub fn contains(&self, key: &Location) -> bool {
+        key.to_hash()
+            .is_ok_and(|hash| self.read().contains_key(&hash))
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Objects> {
+        self.objects.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Objects> {
+        self.objects.write().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl storage::StorageGet for InMemoryStorage {
+    #[instrument(skip(self), err)]
+    async fn get(
+        &self,
+        key: &Location,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let hash = key.to_hash()?;
+        let object = self.read().get(&hash).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Object not found: {}", key),
+            )
+        })?;
+
+        Ok(Box::new(Cursor::new(object)))
+    }
+}
+
+#[async_trait]
+impl storage::StoragePut for InMemoryStorage {
+    #[instrument(skip(self, reader), ret, err)]
+    async fn put(
+        &self,
+        mut reader: reader::StreamReadSeeker,
+        len: u64,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<Location> {
+        let mut buffer = storage::object_buffer(len);
+        reader.read_to_end(&mut buffer).await?;
+
+        let hash = *blake3::hash(&buffer).as_bytes();
+        self.write()
+            .entry(hash)
+            .or_insert_with(|| Arc::from(buffer));
+
+        Ok(Location::hash(&hash))
+    }
+}
+
+#[async_trait]
+impl storage::StorageList for InMemoryStorage {
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        Ok(self.keys())
+    }
+}
+
+#[async_trait]
+impl storage::StorageDelete for InMemoryStorage {
+    #[instrument(skip(self), err)]
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        let hash = key.to_hash()?;
+        self.write().remove(&hash).map(|_| ()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Object not found: {}", key),
+            )
+        })
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::{self, Cursor};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::io::AsyncReadExt;
+use tracing::instrument;
+
+use crate::{location::Location, reader, storage};
+
+/// Writes every object to all replicas and reads it from the first replica
+/// that returns it intact, falling back to the next one when an object is
+/// missing, unreadable or does not match its hash.
+///
+/// Keys hold the hash of the object and its key in each replica, so replicas
+/// must be given in the same order when reading.
+#[derive(Debug)]
+pub struct MirrorStorage<S> {
+    replicas: Vec<S>,
+}
+
+impl<S> MirrorStorage<S> {
+    pub fn new(replicas: Vec<S>) -> Self {
+        Self { replicas }
+    }
+
+    pub fn replicas(&self) -> &[S] {
+        &self.replicas
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageGet> storage::StorageGet for MirrorStorage<S> {
+    #[instrument(skip(self), err)]
+    async fn get(
+        &self,
+        key: &Location,
+        kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let (hash, keys) = key.to_mirror()?;
+
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "object has no replica");
+
+        for (index, (replica, replica_key)) in self.replicas.iter().zip(&keys).enumerate() {
+            let mut buffer = Vec::new();
+            let result = async {
+                replica
+                    .get(replica_key, kind)
+                    .await?
+                    .read_to_end(&mut buffer)
+                    .await
+            }
+            .await;
+
+            let error = match result {
+                Ok(_) if blake3::hash(&buffer) == hash => {
+                    return Ok(Box::new(Cursor::new(buffer)));
+                }
+                Ok(_) => io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "replica {} returned corrupted object {}",
+                        index,
+                        blake3::Hash::from_bytes(hash).to_hex()
+                    ),
+                ),
+                Err(e) => e,
+            };
+
+            tracing::warn!("replica {} failed: {}", index, error);
+            last_error = error;
+        }
+
+        Err(last_error)
+    }
+}
+
+#[async_trait]
+impl<S: storage::StoragePut> storage::StoragePut for MirrorStorage<S> {
+    #[instrument(skip(self, reader), ret, err)]
+    async fn put(
+        &self,
+        mut reader: reader::StreamReadSeeker,
+        len: u64,
+        kind: storage::ObjectKind,
+    ) -> io::Result<Location> {
+        let mut buffer = storage::object_buffer(len);
+        reader.read_to_end(&mut buffer).await?;
+
+        let hash = blake3::hash(&buffer);
+
+        let mut keys = Vec::with_capacity(self.replicas.len());
+        for replica in self.replicas.iter() {
+            let reader = Box::new(Cursor::new(buffer.clone()));
+            keys.push(replica.put(reader, len, kind).await?);
+        }
+
+        Ok(Location::mirror(hash.as_bytes(), &keys))
+    }
+
+    #[instrument(skip(self, objects), err)]
+    async fn put_batch(
+        &self,
+        objects: &[Bytes],
+        kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Location>> {
+        let mut replica_keys = Vec::with_capacity(self.replicas.len());
+        for replica in self.replicas.iter() {
+            replica_keys.push(replica.put_batch(objects, kind).await?);
+        }
+
+        let keys = objects
+            .iter()
+            .enumerate()
+            .map(|(index, object)| {
+                let keys: Vec<_> = replica_keys.iter().map(|v| v[index].clone()).collect();
+                Location::mirror(blake3::hash(object).as_bytes(), &keys)
+            })
+            .collect();
+
+        Ok(keys)
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        for replica in self.replicas.iter() {
+            replica.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageDelete> storage::StorageDelete for MirrorStorage<S> {
+    #[instrument(skip(self), err)]
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        let (_, keys) = key.to_mirror()?;
+
+        for (replica, replica_key) in self.replicas.iter().zip(&keys) {
+            match replica.delete(replica_key).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,18 +1,284 @@
-use std::io;
+use std::{
+    io::{self, Cursor},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
-use crate::reader;
-pub use blob::BlobFileStorage;
+use crate::{location::Location, reader};
+pub use blob::{BlobFileStorage, BlobRecord};
+pub use cache::CachedStorage;
+pub use directory::DirectoryStorage;
+pub use dry_run::DryRunStorage;
+pub use encrypted::EncryptedStorage;
+pub use memory::InMemoryStorage;
+pub use mirror::MirrorStorage;
+pub use pack::PackStorage;
+pub use remote::{REMOTE_COMMAND_ENV, RemoteStorage, serve};
+pub use retry::{RetryPolicy, RetryStorage, is_transient};
+#[cfg(feature = "s3")]
+pub use s3::{S3Config, S3Storage};
+pub use url::StorageUrl;
 
 mod blob;
+mod cache;
+mod directory;
+mod dry_run;
+mod encrypted;
+mod memory;
+mod mirror;
+mod pack;
+mod remote;
+mod retry;
+#[cfg(feature = "s3")]
+mod s3;
+mod url;
 
 #[async_trait]
 pub trait StorageGet: Send + Sync {
-    async fn get(&self, key: &str) -> io::Result<reader::StreamReadSeeker>;
+    /// Reads a whole object. `kind` is what the caller expects it to hold;
+    /// storages that authenticate it, such as [`EncryptedStorage`], fail when
+    /// the object was stored as another kind.
+    async fn get(&self, key: &Location, kind: ObjectKind) -> io::Result<reader::StreamReadSeeker>;
+
+    /// Reads the `len` bytes of an object starting at `offset`. Storages that
+    /// can fetch part of an object override this default, which seeks in the
+    /// stream returned by [`get`](Self::get).
+    async fn get_range(
+        &self,
+        key: &Location,
+        offset: u64,
+        len: u64,
+        kind: ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let mut reader = self.get(key, kind).await?;
+        reader.seek(io::SeekFrom::Start(offset)).await?;
+
+        Ok(Box::new(reader::SliceAsyncReader::new(reader, len)))
+    }
+
+    /// Reads several whole objects, in the order of `keys`. Storages that
+    /// pay for every request override this default, which reads them one by
+    /// one.
+    async fn get_batch(&self, keys: &[Location], kind: ObjectKind) -> io::Result<Vec<Vec<u8>>> {
+        let mut objects = Vec::with_capacity(keys.len());
+        for key in keys {
+            let mut buffer = Vec::new();
+            self.get(key, kind).await?.read_to_end(&mut buffer).await?;
+            objects.push(buffer);
+        }
+
+        Ok(objects)
+    }
 }
 
 #[async_trait]
 pub trait StoragePut: Send + Sync {
-    async fn put(&self, reader: reader::StreamReadSeeker, len: u64) -> io::Result<String>;
+    async fn put(
+        &self,
+        reader: reader::StreamReadSeeker,
+        len: u64,
+        kind: ObjectKind,
+    ) -> io::Result<Location>;
+
+    /// Stores several objects of the same kind, returning their keys in
+    /// order. Storages that can write many objects in one request override
+    /// this default, which stores them one by one.
+    async fn put_batch(&self, objects: &[Bytes], kind: ObjectKind) -> io::Result<Vec<Location>> {
+        let mut keys = Vec::with_capacity(objects.len());
+        for object in objects {
+            let reader = Box::new(Cursor::new(object.clone()));
+            keys.push(self.put(reader, object.len() as u64, kind).await?);
+        }
+
+        Ok(keys)
+    }
+
+    /// Makes every object stored so far durable. Storages that persist each
+    /// object before returning its key keep this default.
+    async fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What an object holds, recorded by storages that can describe their
+/// objects so a repository can be recovered without its index files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Chunk,
+    Snapshot,
+}
+
+/// When a storage writing to local files asks the system to persist them.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncPolicy {
+    /// After every object.
+    Always,
+    /// On [`StoragePut::flush`], which commands call before reporting a
+    /// snapshot key.
+    #[default]
+    Flush,
+    /// Never, leaving it to the system. A crash may lose recent snapshots.
+    Never,
+}
+
+#[async_trait]
+pub trait StorageList: Send + Sync {
+    async fn list(&self) -> io::Result<Vec<Location>>;
+}
+
+#[async_trait]
+pub trait StorageDelete: Send + Sync {
+    async fn delete(&self, key: &Location) -> io::Result<()>;
+}
+
+/// Most bytes reserved up front for an object of announced length. Larger
+/// objects grow their buffer as bytes actually arrive, so a wrong length
+/// cannot exhaust memory.
+const MAX_PREALLOCATION: u64 = 16 * 1024 * 1024;
+
+/// Returns an empty buffer for reading an object announced as `len` bytes.
+pub(crate) fn object_buffer(len: u64) -> Vec<u8> {
+    Vec::with_capacity(len.min(MAX_PREALLOCATION) as usize)
+}
+
+/// Checks that the `len` bytes at `offset` lie within an object of `length`
+/// bytes, returning `offset`.
+pub(crate) fn check_range(length: u64, offset: u64, len: u64) -> io::Result<u64> {
+    match offset.checked_add(len) {
+        Some(end) if end <= length => Ok(offset),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid range: {} bytes at {} of an object of {} bytes",
+                len, offset, length
+            ),
+        )),
+    }
+}
+
+/// A storage that can be both read and written, so different backends can be
+/// mixed behind `Box<dyn Storage>`.
+pub trait Storage: StorageGet + StoragePut {}
+
+impl<T: StorageGet + StoragePut + ?Sized> Storage for T {}
+
+/// A storage that can also list and delete its objects, which every backend
+/// a [`StorageUrl`] opens can, so maintenance commands work with any of them.
+pub trait ManagedStorage: Storage + StorageList + StorageDelete {}
+
+impl<T: Storage + StorageList + StorageDelete + ?Sized> ManagedStorage for T {}
+
+#[async_trait]
+impl<T: StorageGet + ?Sized> StorageGet for Box<T> {
+    async fn get(&self, key: &Location, kind: ObjectKind) -> io::Result<reader::StreamReadSeeker> {
+        (**self).get(key, kind).await
+    }
+
+    async fn get_range(
+        &self,
+        key: &Location,
+        offset: u64,
+        len: u64,
+        kind: ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        (**self).get_range(key, offset, len, kind).await
+    }
+
+    async fn get_batch(&self, keys: &[Location], kind: ObjectKind) -> io::Result<Vec<Vec<u8>>> {
+        (**self).get_batch(keys, kind).await
+    }
+}
+
+#[async_trait]
+impl<T: StoragePut + ?Sized> StoragePut for Box<T> {
+    async fn put(
+        &self,
+        reader: reader::StreamReadSeeker,
+        len: u64,
+        kind: ObjectKind,
+    ) -> io::Result<Location> {
+        (**self).put(reader, len, kind).await
+    }
+
+    async fn put_batch(&self, objects: &[Bytes], kind: ObjectKind) -> io::Result<Vec<Location>> {
+        (**self).put_batch(objects, kind).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        (**self).flush().await
+    }
+}
+
+#[async_trait]
+impl<T: StorageGet + ?Sized> StorageGet for Arc<T> {
+    async fn get(&self, key: &Location, kind: ObjectKind) -> io::Result<reader::StreamReadSeeker> {
+        (**self).get(key, kind).await
+    }
+
+    async fn get_range(
+        &self,
+        key: &Location,
+        offset: u64,
+        len: u64,
+        kind: ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        (**self).get_range(key, offset, len, kind).await
+    }
+
+    async fn get_batch(&self, keys: &[Location], kind: ObjectKind) -> io::Result<Vec<Vec<u8>>> {
+        (**self).get_batch(keys, kind).await
+    }
+}
+
+#[async_trait]
+impl<T: StoragePut + ?Sized> StoragePut for Arc<T> {
+    async fn put(
+        &self,
+        reader: reader::StreamReadSeeker,
+        len: u64,
+        kind: ObjectKind,
+    ) -> io::Result<Location> {
+        (**self).put(reader, len, kind).await
+    }
+
+    async fn put_batch(&self, objects: &[Bytes], kind: ObjectKind) -> io::Result<Vec<Location>> {
+        (**self).put_batch(objects, kind).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        (**self).flush().await
+    }
+}
+
+#[async_trait]
+impl<T: StorageList + ?Sized> StorageList for Box<T> {
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        (**self).list().await
+    }
+}
+
+#[async_trait]
+impl<T: StorageDelete + ?Sized> StorageDelete for Box<T> {
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        (**self).delete(key).await
+    }
+}
+
+#[async_trait]
+impl<T: StorageList + ?Sized> StorageList for Arc<T> {
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        (**self).list().await
+    }
+}
+
+#[async_trait]
+impl<T: StorageDelete + ?Sized> StorageDelete for Arc<T> {
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        (**self).delete(key).await
+    }
 }
@@ -0,0 +1,527 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{Mutex, RwLock},
+};
+use tracing::instrument;
+
+use crate::{
+    location::Location,
+    reader::{self, SliceAsyncReader},
+    storage,
+};
+
+const PACK_EXTENSION: &str = "pack";
+const INDEX_EXTENSION: &str = "idx";
+const DELETED_FILE: &str = "deleted";
+const DEFAULT_PACK_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+struct PackEntry {
+    pack: u32,
+    offset: u64,
+    length: u64,
+}
+
+/// One record of a pack index: where an object with `hash` lives in the pack.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct IndexEntry {
+    hash: [u8; 32],
+    offset: u64,
+    length: u64,
+}
+
+/// One record of the deletion log: `hash` is no longer stored in `pack`.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Hash, Clone, Copy)]
+struct Tombstone {
+    pack: u32,
+    hash: [u8; 32],
+}
+
+#[derive(Debug)]
+struct OpenPack {
+    id: u32,
+    pack: fs::File,
+    index: fs::File,
+    size: u64,
+}
+
+impl OpenPack {
+    async fn sync(&self) -> io::Result<()> {
+        self.pack.sync_all().await?;
+        self.index.sync_all().await
+    }
+
+    /// Syncs the pack without a runtime, for when the storage is dropped.
+    fn sync_blocking(self) -> io::Result<()> {
+        for file in [self.pack, self.index] {
+            let file = file
+                .try_into_std()
+                .map_err(|_| io::Error::other("pack file has a pending operation"))?;
+            file.sync_all()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Groups objects into pack files of at most `max_pack_size` bytes, each with
+/// an index listing the objects it holds. A pack is never written again once
+/// it is full or the storage is dropped, so copies only need to transfer new
+/// packs and pruning only needs to rewrite packs with unused objects.
+///
+/// Keys are the blake3 hash of the object and are resolved through the pack
+/// indexes, so objects keep their key when [`PackStorage::repack`] moves them.
+#[derive(Debug)]
+pub struct PackStorage<const WRITE: bool> {
+    root: PathBuf,
+    max_pack_size: u64,
+    known: RwLock<HashMap<[u8; 32], PackEntry>>,
+    current: Mutex<Option<OpenPack>>,
+    next_id: Mutex<u32>,
+    sync_policy: storage::SyncPolicy,
+}
+
+impl<const WRITE: bool> PackStorage<WRITE> {
+    #[instrument(err)]
+    pub async fn new<P: Into<PathBuf> + Debug>(path: P) -> io::Result<Self> {
+        Self::with_pack_size(path, DEFAULT_PACK_SIZE).await
+    }
+
+    #[instrument(err)]
+    pub async fn with_pack_size<P: Into<PathBuf> + Debug>(
+        path: P,
+        max_pack_size: u64,
+    ) -> io::Result<Self> {
+        let root = path.into();
+
+        if WRITE {
+            fs::create_dir_all(&root).await?;
+        }
+
+        let mut known = HashMap::new();
+        let mut next_id = 0;
+
+        let tombstones: HashSet<Tombstone> = read_records(&root.join(DELETED_FILE))
+            .await?
+            .into_iter()
+            .collect();
+
+        if fs::try_exists(&root).await? {
+            let mut dir = fs::read_dir(&root).await?;
+            while let Some(entry) = dir.next_entry().await? {
+                let path = entry.path();
+                let id = match parse_pack_id(&path) {
+                    Some(v) => v,
+                    None => continue,
+                };
+                next_id = next_id.max(id + 1);
+
+                if path.extension().is_some_and(|v| v == INDEX_EXTENSION) {
+                    for entry in read_records::<IndexEntry>(&path).await? {
+                        let tombstone = Tombstone {
+                            pack: id,
+                            hash: entry.hash,
+                        };
+                        if tombstones.contains(&tombstone) {
+                            continue;
+                        }
+
+                        known.insert(
+                            entry.hash,
+                            PackEntry {
+                                pack: id,
+                                offset: entry.offset,
+                                length: entry.length,
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            root,
+            max_pack_size,
+            known: RwLock::new(known),
+            current: Mutex::new(None),
+            next_id: Mutex::new(next_id),
+            sync_policy: storage::SyncPolicy::default(),
+        })
+    }
+
+    /// Sets when packs are synced. Full packs are synced when they are sealed
+    /// unless the policy is [`Never`](storage::SyncPolicy::Never).
+    pub fn sync_policy(mut self, policy: storage::SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    fn pack_path(&self, id: u32, extension: &str) -> PathBuf {
+        self.root.join(format!("{:08x}.{}", id, extension))
+    }
+}
+
+impl PackStorage<true> {
+    async fn open_pack(&self) -> io::Result<OpenPack> {
+        let id = {
+            let mut next_id = self.next_id.lock().await;
+            let id = *next_id;
+            *next_id += 1;
+            id
+        };
+
+        let pack = fs::OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(self.pack_path(id, PACK_EXTENSION))
+            .await?;
+        let index = fs::OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(self.pack_path(id, INDEX_EXTENSION))
+            .await?;
+
+        Ok(OpenPack {
+            id,
+            pack,
+            index,
+            size: 0,
+        })
+    }
+
+    async fn append(
+        &self,
+        current: &mut Option<OpenPack>,
+        hash: [u8; 32],
+        buffer: &[u8],
+    ) -> io::Result<()> {
+        let mut open_pack = match current.take() {
+            Some(v) => v,
+            None => self.open_pack().await?,
+        };
+
+        let offset = open_pack.size;
+        open_pack.pack.write_all(buffer).await?;
+        open_pack.pack.flush().await?;
+        open_pack.size += buffer.len() as u64;
+
+        let index_entry = IndexEntry {
+            hash,
+            offset,
+            length: buffer.len() as u64,
+        };
+        let index_entry = bincode::serialize(&index_entry).map_err(io::Error::other)?;
+        open_pack.index.write_all(&index_entry).await?;
+        open_pack.index.flush().await?;
+
+        let entry = PackEntry {
+            pack: open_pack.id,
+            offset,
+            length: buffer.len() as u64,
+        };
+        self.known.write().await.insert(hash, entry);
+
+        let seal = open_pack.size >= self.max_pack_size;
+
+        match self.sync_policy {
+            storage::SyncPolicy::Always => open_pack.sync().await?,
+            storage::SyncPolicy::Flush if seal => open_pack.sync().await?,
+            _ => {}
+        }
+
+        if !seal {
+            *current = Some(open_pack);
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites every pack whose share of deleted bytes exceeds
+    /// `max_unused_ratio`, moving its remaining objects into new packs, and
+    /// removes packs that hold no object anymore.
+    #[instrument(err)]
+    pub async fn repack(&self, max_unused_ratio: f64) -> io::Result<()> {
+        let mut current = self.current.lock().await;
+        let open_id = current.as_ref().map(|v| v.id);
+
+        let mut live: HashMap<u32, Vec<([u8; 32], PackEntry)>> = HashMap::new();
+        for (hash, entry) in self.known.read().await.iter() {
+            live.entry(entry.pack).or_default().push((*hash, *entry));
+        }
+
+        let mut removed = HashSet::new();
+
+        let mut dir = fs::read_dir(&self.root).await?;
+        while let Some(dir_entry) = dir.next_entry().await? {
+            let path = dir_entry.path();
+            if path.extension().is_none_or(|v| v != PACK_EXTENSION) {
+                continue;
+            }
+            let id = match parse_pack_id(&path) {
+                Some(v) if Some(v) != open_id => v,
+                _ => continue,
+            };
+
+            let size = dir_entry.metadata().await?.len();
+            let entries = live.remove(&id).unwrap_or_default();
+            let used: u64 = entries.iter().map(|(_, v)| v.length).sum();
+
+            if size > 0 && (size - used) as f64 / size as f64 <= max_unused_ratio {
+                continue;
+            }
+
+            let mut pack = fs::File::open(&path).await?;
+            for (hash, entry) in entries {
+                let mut buffer = vec![0u8; entry.length as usize];
+                pack.seek(SeekFrom::Start(entry.offset)).await?;
+                pack.read_exact(&mut buffer).await?;
+
+                self.append(&mut current, hash, &buffer).await?;
+            }
+
+            removed.insert(id);
+        }
+
+        if let Some(open_pack) = current.as_mut() {
+            open_pack.sync().await?;
+        }
+
+        for id in removed.iter() {
+            fs::remove_file(self.pack_path(*id, INDEX_EXTENSION)).await?;
+            fs::remove_file(self.pack_path(*id, PACK_EXTENSION)).await?;
+        }
+
+        let deleted_path = self.root.join(DELETED_FILE);
+        let tombstones: Vec<Tombstone> = read_records::<Tombstone>(&deleted_path)
+            .await?
+            .into_iter()
+            .filter(|v| !removed.contains(&v.pack))
+            .collect();
+
+        let mut buffer = Vec::new();
+        for tombstone in tombstones.iter() {
+            buffer.extend(bincode::serialize(tombstone).map_err(io::Error::other)?);
+        }
+        let temp_path = deleted_path.with_extension("tmp");
+        fs::write(&temp_path, buffer).await?;
+        fs::rename(temp_path, deleted_path).await?;
+
+        Ok(())
+    }
+}
+
+/// Seals the partial pack, syncing it unless the policy is
+/// [`Never`](storage::SyncPolicy::Never), so the objects it holds survive a
+/// crash even if [`flush`](storage::StoragePut::flush) was never called.
+impl<const WRITE: bool> Drop for PackStorage<WRITE> {
+    fn drop(&mut self) {
+        if self.sync_policy == storage::SyncPolicy::Never {
+            return;
+        }
+
+        if let Some(open_pack) = self.current.get_mut().take()
+            && let Err(e) = open_pack.sync_blocking()
+        {
+            tracing::warn!("failed to sync pack: {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl<const WRITE: bool> storage::StorageGet for PackStorage<WRITE> {
+    #[instrument(err)]
+    async fn get(
+        &self,
+        key: &Location,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let hash = key.to_hash()?;
+        let entry = self.known.read().await.get(&hash).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Object not found: {}", key),
+            )
+        })?;
+
+        let mut file = fs::File::open(self.pack_path(entry.pack, PACK_EXTENSION)).await?;
+
+        file.seek(SeekFrom::Start(entry.offset)).await?;
+        let limited_reader = SliceAsyncReader::new(file, entry.length);
+
+        Ok(Box::new(limited_reader))
+    }
+
+    #[instrument(err)]
+    async fn get_range(
+        &self,
+        key: &Location,
+        offset: u64,
+        len: u64,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let hash = key.to_hash()?;
+        let entry = self.known.read().await.get(&hash).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Object not found: {}", key),
+            )
+        })?;
+        let start = entry.offset + storage::check_range(entry.length, offset, len)?;
+
+        let mut file = fs::File::open(self.pack_path(entry.pack, PACK_EXTENSION)).await?;
+
+        file.seek(SeekFrom::Start(start)).await?;
+        let limited_reader = SliceAsyncReader::new(file, len);
+
+        Ok(Box::new(limited_reader))
+    }
+}
+
+#[async_trait]
+impl storage::StoragePut for PackStorage<true> {
+    #[instrument(skip(reader), ret, err)]
+    async fn put(
+        &self,
+        mut reader: reader::StreamReadSeeker,
+        len: u64,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<Location> {
+        let mut buffer = storage::object_buffer(len);
+        reader.read_to_end(&mut buffer).await?;
+
+        let hash = blake3::hash(&buffer);
+        let key = Location::hash(hash.as_bytes());
+
+        if self.known.read().await.contains_key(hash.as_bytes()) {
+            return Ok(key);
+        }
+
+        let mut current = self.current.lock().await;
+        // Another put may have stored the same object while this one waited.
+        if !self.known.read().await.contains_key(hash.as_bytes()) {
+            self.append(&mut current, *hash.as_bytes(), &buffer).await?;
+        }
+
+        Ok(key)
+    }
+
+    /// Appends every new object to the current pack while holding it once.
+    #[instrument(skip(self, objects), err)]
+    async fn put_batch(
+        &self,
+        objects: &[Bytes],
+        _kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Location>> {
+        let mut current = self.current.lock().await;
+
+        let mut keys = Vec::with_capacity(objects.len());
+        for object in objects {
+            let hash = blake3::hash(object);
+
+            if !self.known.read().await.contains_key(hash.as_bytes()) {
+                self.append(&mut current, *hash.as_bytes(), object).await?;
+            }
+
+            keys.push(Location::hash(hash.as_bytes()));
+        }
+
+        Ok(keys)
+    }
+
+    #[instrument(err)]
+    async fn flush(&self) -> io::Result<()> {
+        if self.sync_policy == storage::SyncPolicy::Never {
+            return Ok(());
+        }
+
+        match self.current.lock().await.as_mut() {
+            Some(open_pack) => open_pack.sync().await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<const WRITE: bool> storage::StorageList for PackStorage<WRITE> {
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        let keys = self.known.read().await.keys().map(Location::hash).collect();
+
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl storage::StorageDelete for PackStorage<true> {
+    #[instrument(err)]
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        let hash = key.to_hash()?;
+
+        let mut known = self.known.write().await;
+        let entry = known.get(&hash).copied().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Object not found: {}", key),
+            )
+        })?;
+
+        let tombstone = Tombstone {
+            pack: entry.pack,
+            hash,
+        };
+        append_record(&self.root.join(DELETED_FILE), &tombstone).await?;
+        known.remove(&hash);
+
+        Ok(())
+    }
+}
+
+fn parse_pack_id(path: &Path) -> Option<u32> {
+    let stem = path.file_stem()?.to_str()?;
+    if stem.len() != 8 {
+        return None;
+    }
+
+    u32::from_str_radix(stem, 16).ok()
+}
+
+/// Reads a file of fixed-size bincode records. A trailing partial record is
+/// left behind by an interrupted write and is ignored.
+async fn read_records<T: DeserializeOwned + Default + Serialize>(
+    path: &Path,
+) -> io::Result<Vec<T>> {
+    let buffer = match fs::read(path).await {
+        Ok(v) => v,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let record_size = bincode::serialized_size(&T::default()).map_err(io::Error::other)? as usize;
+
+    buffer
+        .chunks_exact(record_size)
+        .map(|v| bincode::deserialize(v).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+        .collect()
+}
+
+async fn append_record<T: Serialize>(path: &Path, record: &T) -> io::Result<()> {
+    let buffer = bincode::serialize(record).map_err(io::Error::other)?;
+
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(&buffer).await?;
+    file.sync_all().await
+}
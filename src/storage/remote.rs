@@ -0,0 +1,643 @@
+use std::{
+    fmt::{self, Debug},
+    io::{self, Cursor},
+    process::Stdio,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    process::{Child, Command},
+    sync::Mutex,
+};
+use tracing::instrument;
+
+use crate::{
+    location::Location,
+    reader,
+    storage::{self, StorageUrl},
+};
+
+/// Sent by the server when it starts, so the client can tell the protocol
+/// apart from anything a login shell might print.
+const MAGIC: &[u8; 8] = b"LEPATCH3";
+
+const OP_GET: u8 = 1;
+const OP_PUT_CHUNK: u8 = 2;
+const OP_FLUSH: u8 = 3;
+const OP_PUT_SNAPSHOT: u8 = 4;
+const OP_GET_RANGE: u8 = 5;
+const OP_GET_BATCH: u8 = 6;
+const OP_PUT_BATCH_CHUNK: u8 = 7;
+const OP_PUT_BATCH_SNAPSHOT: u8 = 8;
+const OP_LIST: u8 = 9;
+const OP_DELETE: u8 = 10;
+
+/// Leads the payload of get requests.
+const KIND_CHUNK: u8 = 0;
+const KIND_SNAPSHOT: u8 = 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+
+/// Environment variable overriding the program run on the remote host. It is
+/// passed to the remote shell as is, so it may hold arguments.
+pub const REMOTE_COMMAND_ENV: &str = "LEPATCH_REMOTE_COMMAND";
+/// Name of the binary built by this crate.
+const DEFAULT_REMOTE_COMMAND: &str = "main";
+
+type BoxedReader = Box<dyn AsyncRead + Unpin + Send>;
+type BoxedWriter = Box<dyn AsyncWrite + Unpin + Send>;
+
+struct Connection {
+    reader: BufReader<BoxedReader>,
+    writer: BufWriter<BoxedWriter>,
+    _child: Option<Child>,
+}
+
+impl Connection {
+    /// Waits for the server to announce the protocol.
+    async fn open(
+        reader: BoxedReader,
+        writer: BoxedWriter,
+        child: Option<Child>,
+    ) -> io::Result<Self> {
+        let mut reader = BufReader::new(reader);
+
+        let mut magic = [0u8; MAGIC.len()];
+        reader.read_exact(&mut magic).await.map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("remote closed the connection before answering: {}", e),
+            )
+        })?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "remote did not answer with the lepatch protocol",
+            ));
+        }
+
+        Ok(Self {
+            reader,
+            writer: BufWriter::new(writer),
+            _child: child,
+        })
+    }
+
+    /// Sends one request and reads its answer. The outer error means the
+    /// exchange did not complete, the inner one was reported by the server.
+    async fn exchange(&mut self, op: u8, payload: &[u8]) -> io::Result<io::Result<Vec<u8>>> {
+        self.writer.write_u8(op).await?;
+        write_bytes(&mut self.writer, payload).await?;
+        self.writer.flush().await?;
+
+        match read_status(&mut self.reader).await? {
+            Ok(()) => Ok(Ok(read_bytes(&mut self.reader).await?)),
+            Err(e) => Ok(Err(e)),
+        }
+    }
+}
+
+/// Client side of the storage protocol spoken by the `serve` command.
+/// Requests are sent one at a time and every object is transferred whole.
+///
+/// Answers carry no request id, so a request that fails or is cancelled
+/// halfway would leave the next one reading its answer. The connection is
+/// closed instead and the request fails with
+/// [`ConnectionAborted`](io::ErrorKind::ConnectionAborted), which
+/// [`RetryStorage`](storage::RetryStorage) retries. Connections made by
+/// [`connect_ssh`](Self::connect_ssh) are opened again by the next request,
+/// taking the lock of the repository again; the others stay closed.
+pub struct RemoteStorage {
+    connection: Mutex<Option<Connection>>,
+    ssh: Option<SshTarget>,
+}
+
+/// How to start the `serve` command on a host again.
+#[derive(Debug)]
+struct SshTarget {
+    host: String,
+    port: Option<u16>,
+    url: StorageUrl,
+    exclusive: bool,
+}
+
+impl SshTarget {
+    async fn connect(&self) -> io::Result<Connection> {
+        let remote_command = std::env::var(REMOTE_COMMAND_ENV)
+            .unwrap_or_else(|_| DEFAULT_REMOTE_COMMAND.to_string());
+
+        // ssh hands the remote shell a single command line, so every
+        // argument after the program is quoted.
+        let mut remote_command = vec![
+            remote_command,
+            shell_quote("--progress"),
+            shell_quote("none"),
+            shell_quote("serve"),
+        ];
+        if !self.exclusive {
+            remote_command.push(shell_quote("--shared"));
+        }
+        remote_command.push(shell_quote(&self.url.to_string()));
+        let remote_command = remote_command.join(" ");
+
+        let mut command = Command::new("ssh");
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        command
+            .arg("--")
+            .arg(&self.host)
+            .arg(remote_command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true);
+
+        let mut child = command.spawn()?;
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| io::Error::other("ssh stdin is not captured"))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| io::Error::other("ssh stdout is not captured"))?;
+
+        Connection::open(Box::new(stdout), Box::new(stdin), Some(child)).await
+    }
+}
+
+impl Debug for RemoteStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteStorage").finish_non_exhaustive()
+    }
+}
+
+impl RemoteStorage {
+    /// Talks to a server reading requests from `writer` and answering on
+    /// `reader`.
+    pub async fn new<R, W>(reader: R, writer: W) -> io::Result<Self>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let connection = Connection::open(Box::new(reader), Box::new(writer), None).await?;
+
+        Ok(Self {
+            connection: Mutex::new(Some(connection)),
+            ssh: None,
+        })
+    }
+
+    /// Starts the `serve` command for `url` on `host` through ssh. It locks
+    /// the repository until the connection closes, exclusively when
+    /// `exclusive` is set.
+    #[instrument(err)]
+    pub async fn connect_ssh(
+        host: &str,
+        port: Option<u16>,
+        url: &StorageUrl,
+        exclusive: bool,
+    ) -> io::Result<Self> {
+        let target = SshTarget {
+            host: host.to_string(),
+            port,
+            url: url.clone(),
+            exclusive,
+        };
+        let connection = target.connect().await?;
+
+        Ok(Self {
+            connection: Mutex::new(Some(connection)),
+            ssh: Some(target),
+        })
+    }
+
+    /// Sends one request and returns the answer. The connection is taken for
+    /// the exchange and only put back once it completed, so a failure of the
+    /// connection or a cancelled request closes it.
+    async fn request(&self, op: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let mut slot = self.connection.lock().await;
+        let mut connection = match (slot.take(), &self.ssh) {
+            (Some(connection), _) => connection,
+            (None, Some(target)) => {
+                tracing::warn!("connecting to {} again", target.host);
+                target.connect().await?
+            }
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "connection to the remote was closed after an earlier failure",
+                ));
+            }
+        };
+
+        let answer = connection.exchange(op, payload).await.map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("connection to the remote failed: {}", e),
+            )
+        })?;
+        *slot = Some(connection);
+
+        answer
+    }
+}
+
+#[async_trait]
+impl storage::StorageGet for RemoteStorage {
+    #[instrument(err)]
+    async fn get(
+        &self,
+        key: &Location,
+        kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let mut payload = vec![kind_to_byte(kind)];
+        payload.extend_from_slice(key.as_bytes());
+
+        let buffer = self.request(OP_GET, &payload).await?;
+
+        Ok(Box::new(Cursor::new(buffer)))
+    }
+
+    #[instrument(err)]
+    async fn get_range(
+        &self,
+        key: &Location,
+        offset: u64,
+        len: u64,
+        kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let mut payload = Vec::with_capacity(17 + key.as_bytes().len());
+        payload.push(kind_to_byte(kind));
+        payload.extend_from_slice(&offset.to_be_bytes());
+        payload.extend_from_slice(&len.to_be_bytes());
+        payload.extend_from_slice(key.as_bytes());
+
+        let buffer = self.request(OP_GET_RANGE, &payload).await?;
+
+        Ok(Box::new(Cursor::new(buffer)))
+    }
+
+    #[instrument(err)]
+    async fn get_batch(
+        &self,
+        keys: &[Location],
+        kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let mut payload = vec![kind_to_byte(kind)];
+        payload.extend(encode_list(keys.iter().map(|v| v.as_bytes())));
+
+        let objects = decode_list(&self.request(OP_GET_BATCH, &payload).await?)?;
+
+        check_count(objects, keys.len())
+    }
+}
+
+#[async_trait]
+impl storage::StoragePut for RemoteStorage {
+    #[instrument(skip(reader), ret, err)]
+    async fn put(
+        &self,
+        mut reader: reader::StreamReadSeeker,
+        len: u64,
+        kind: storage::ObjectKind,
+    ) -> io::Result<Location> {
+        let mut buffer = storage::object_buffer(len);
+        reader.read_to_end(&mut buffer).await?;
+
+        let op = match kind {
+            storage::ObjectKind::Chunk => OP_PUT_CHUNK,
+            storage::ObjectKind::Snapshot => OP_PUT_SNAPSHOT,
+        };
+
+        let key = self.request(op, &buffer).await?;
+
+        Location::from_bytes(key)
+    }
+
+    #[instrument(skip(self, objects), err)]
+    async fn put_batch(
+        &self,
+        objects: &[Bytes],
+        kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Location>> {
+        let payload = encode_list(objects.iter().map(Bytes::as_ref));
+
+        let op = match kind {
+            storage::ObjectKind::Chunk => OP_PUT_BATCH_CHUNK,
+            storage::ObjectKind::Snapshot => OP_PUT_BATCH_SNAPSHOT,
+        };
+
+        let keys = decode_list(&self.request(op, &payload).await?)?;
+
+        check_count(keys, objects.len())?
+            .into_iter()
+            .map(Location::from_bytes)
+            .collect()
+    }
+
+    #[instrument(err)]
+    async fn flush(&self) -> io::Result<()> {
+        self.request(OP_FLUSH, &[]).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl storage::StorageList for RemoteStorage {
+    #[instrument(err)]
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        decode_list(&self.request(OP_LIST, &[]).await?)?
+            .into_iter()
+            .map(Location::from_bytes)
+            .collect()
+    }
+}
+
+#[async_trait]
+impl storage::StorageDelete for RemoteStorage {
+    #[instrument(err)]
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        self.request(OP_DELETE, key.as_bytes()).await?;
+
+        Ok(())
+    }
+}
+
+/// Answers requests read from `reader` with `storage` until the client closes
+/// the connection. Errors of the storage are sent back to the client; only
+/// errors of the connection itself end the loop.
+pub async fn serve<S, R, W>(storage: &S, reader: R, writer: W) -> io::Result<()>
+where
+    S: storage::ManagedStorage + ?Sized,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    writer.write_all(MAGIC).await?;
+    writer.flush().await?;
+
+    loop {
+        let op = match reader.read_u8().await {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let payload = read_bytes(&mut reader).await?;
+
+        let result = match op {
+            OP_GET => {
+                async {
+                    let (kind, key) = split_kind(&payload)?;
+                    let key = Location::from_bytes(key.to_vec())?;
+                    let mut buffer = Vec::new();
+                    storage
+                        .get(&key, kind)
+                        .await?
+                        .read_to_end(&mut buffer)
+                        .await?;
+                    Ok(buffer)
+                }
+                .await
+            }
+            OP_GET_RANGE => {
+                async {
+                    let (kind, payload) = split_kind(&payload)?;
+                    if payload.len() < 16 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "truncated range request",
+                        ));
+                    }
+                    let (range, key) = payload.split_at(16);
+                    let offset = u64::from_be_bytes(range[..8].try_into().expect("8 bytes"));
+                    let len = u64::from_be_bytes(range[8..].try_into().expect("8 bytes"));
+
+                    let key = Location::from_bytes(key.to_vec())?;
+                    let mut buffer = Vec::new();
+                    storage
+                        .get_range(&key, offset, len, kind)
+                        .await?
+                        .read_to_end(&mut buffer)
+                        .await?;
+                    Ok(buffer)
+                }
+                .await
+            }
+            OP_GET_BATCH => {
+                async {
+                    let (kind, payload) = split_kind(&payload)?;
+                    let keys = decode_list(payload)?
+                        .into_iter()
+                        .map(Location::from_bytes)
+                        .collect::<io::Result<Vec<_>>>()?;
+                    let objects = storage.get_batch(&keys, kind).await?;
+
+                    Ok(encode_list(objects.iter().map(Vec::as_slice)))
+                }
+                .await
+            }
+            OP_PUT_BATCH_CHUNK | OP_PUT_BATCH_SNAPSHOT => {
+                async {
+                    let kind = if op == OP_PUT_BATCH_SNAPSHOT {
+                        storage::ObjectKind::Snapshot
+                    } else {
+                        storage::ObjectKind::Chunk
+                    };
+
+                    let objects: Vec<_> = decode_list(&payload)?
+                        .into_iter()
+                        .map(Bytes::from)
+                        .collect();
+                    let keys = storage.put_batch(&objects, kind).await?;
+
+                    Ok(encode_list(keys.iter().map(Location::as_bytes)))
+                }
+                .await
+            }
+            OP_PUT_CHUNK | OP_PUT_SNAPSHOT => {
+                let kind = if op == OP_PUT_SNAPSHOT {
+                    storage::ObjectKind::Snapshot
+                } else {
+                    storage::ObjectKind::Chunk
+                };
+
+                let len = payload.len() as u64;
+                storage
+                    .put(Box::new(Cursor::new(payload)), len, kind)
+                    .await
+                    .map(|v| v.as_bytes().to_vec())
+            }
+            OP_FLUSH => storage.flush().await.map(|_| Vec::new()),
+            OP_LIST => storage
+                .list()
+                .await
+                .map(|keys| encode_list(keys.iter().map(Location::as_bytes))),
+            OP_DELETE => async {
+                let key = Location::from_bytes(payload)?;
+                storage.delete(&key).await
+            }
+            .await
+            .map(|_| Vec::new()),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown remote operation {}", op),
+                ));
+            }
+        };
+
+        match result {
+            Ok(buffer) => {
+                writer.write_u8(STATUS_OK).await?;
+                write_bytes(&mut writer, &buffer).await?;
+            }
+            Err(e) => {
+                writer.write_u8(STATUS_ERROR).await?;
+                writer.write_u8(kind_to_code(e.kind())).await?;
+                write_bytes(&mut writer, e.to_string().as_bytes()).await?;
+            }
+        }
+        writer.flush().await?;
+    }
+}
+
+async fn write_bytes<W: AsyncWrite + Unpin>(writer: &mut W, buffer: &[u8]) -> io::Result<()> {
+    writer.write_u64(buffer.len() as u64).await?;
+    writer.write_all(buffer).await
+}
+
+async fn read_bytes<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u64().await?;
+
+    let mut buffer = Vec::new();
+    let n = (&mut *reader).take(len).read_to_end(&mut buffer).await?;
+    if n as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(buffer)
+}
+
+fn kind_to_byte(kind: storage::ObjectKind) -> u8 {
+    match kind {
+        storage::ObjectKind::Chunk => KIND_CHUNK,
+        storage::ObjectKind::Snapshot => KIND_SNAPSHOT,
+    }
+}
+
+/// Splits the kind of the objects read off the front of a get request.
+fn split_kind(payload: &[u8]) -> io::Result<(storage::ObjectKind, &[u8])> {
+    match payload.split_first() {
+        Some((&KIND_CHUNK, rest)) => Ok((storage::ObjectKind::Chunk, rest)),
+        Some((&KIND_SNAPSHOT, rest)) => Ok((storage::ObjectKind::Snapshot, rest)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "get request without a valid object kind",
+        )),
+    }
+}
+
+/// Packs several byte strings into one frame, each prefixed by its length.
+fn encode_list<'a, I: Iterator<Item = &'a [u8]>>(items: I) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for item in items {
+        buffer.extend_from_slice(&(item.len() as u64).to_be_bytes());
+        buffer.extend_from_slice(item);
+    }
+
+    buffer
+}
+
+fn decode_list(mut buffer: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "truncated remote list");
+
+    let mut items = Vec::new();
+    while !buffer.is_empty() {
+        let (len, rest) = buffer.split_first_chunk::<8>().ok_or_else(truncated)?;
+        let len = usize::try_from(u64::from_be_bytes(*len)).map_err(|_| truncated())?;
+        if rest.len() < len {
+            return Err(truncated());
+        }
+
+        let (item, rest) = rest.split_at(len);
+        items.push(item.to_vec());
+        buffer = rest;
+    }
+
+    Ok(items)
+}
+
+fn check_count<T>(items: Vec<T>, expected: usize) -> io::Result<Vec<T>> {
+    if items.len() != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "remote answered {} items instead of {}",
+                items.len(),
+                expected
+            ),
+        ));
+    }
+
+    Ok(items)
+}
+
+/// Reads the status of an answer. The inner error is the one the server
+/// reported, after which the connection is still usable.
+async fn read_status<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<io::Result<()>> {
+    match reader.read_u8().await? {
+        STATUS_OK => Ok(Ok(())),
+        STATUS_ERROR => {
+            let kind = code_to_kind(reader.read_u8().await?);
+            let message = read_bytes(reader).await?;
+
+            Ok(Err(io::Error::new(
+                kind,
+                format!("remote: {}", String::from_utf8_lossy(&message)),
+            )))
+        }
+        status => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown remote status {}", status),
+        )),
+    }
+}
+
+const KIND_CODES: [io::ErrorKind; 12] = [
+    io::ErrorKind::Other,
+    io::ErrorKind::NotFound,
+    io::ErrorKind::InvalidInput,
+    io::ErrorKind::InvalidData,
+    io::ErrorKind::PermissionDenied,
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::Unsupported,
+    io::ErrorKind::ConnectionReset,
+    io::ErrorKind::ConnectionAborted,
+    io::ErrorKind::BrokenPipe,
+    io::ErrorKind::ResourceBusy,
+];
+
+fn kind_to_code(kind: io::ErrorKind) -> u8 {
+    KIND_CODES.iter().position(|v| *v == kind).unwrap_or(0) as u8
+}
+
+/// Quotes `arg` for a POSIX shell.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+fn code_to_kind(code: u8) -> io::ErrorKind {
+    KIND_CODES
+        .get(code as usize)
+        .copied()
+        .unwrap_or(io::ErrorKind::Other)
+}
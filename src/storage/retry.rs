@@ -0,0 +1,197 @@
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::BuildHasher,
+    io::{self, Cursor},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use tracing::instrument;
+
+use crate::{location::Location, reader, storage};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Number of attempts after the first one, 0 disables retrying.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled on every following one.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 5,
+            initial_backoff_ms: 100,
+            max_backoff_ms: 10_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Waits between half and all of the exponential backoff for `attempt`,
+    /// so concurrent clients do not retry in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX))
+            .min(self.max_backoff_ms);
+
+        // Every `RandomState` is seeded differently, which is all the
+        // randomness jitter needs.
+        let jitter = RandomState::new().hash_one(attempt) % (backoff / 2 + 1);
+
+        Duration::from_millis(backoff - jitter)
+    }
+}
+
+/// Whether an error is likely to go away when the operation is repeated:
+/// interruptions, timeouts, dropped connections and busy servers, which is how
+/// remote backends report throttling and server errors.
+pub fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ResourceBusy
+    )
+}
+
+/// Repeats failed operations of the inner storage on transient errors, waiting
+/// with exponential backoff between attempts.
+///
+/// Writes are retried too, which is safe for content addressed backends. An
+/// append-only backend may keep the bytes of a failed attempt as garbage, but
+/// the returned key always points at a complete copy. Errors while reading
+/// from a returned reader are not retried.
+#[derive(Debug)]
+pub struct RetryStorage<S> {
+    inner: S,
+    policy: RetryPolicy,
+}
+
+impl<S> RetryStorage<S> {
+    pub fn new(inner: S, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    async fn retry<T, F, Fut>(&self, mut operation: F) -> io::Result<T>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = io::Result<T>> + Send,
+    {
+        let mut attempt = 0;
+
+        loop {
+            match operation().await {
+                Err(e) if is_transient(&e) && attempt < self.policy.max_retries => {
+                    let backoff = self.policy.backoff(attempt);
+                    tracing::warn!("transient storage error, retrying in {:?}: {}", backoff, e);
+
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageGet> storage::StorageGet for RetryStorage<S> {
+    #[instrument(skip(self), err)]
+    async fn get(
+        &self,
+        key: &Location,
+        kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        self.retry(|| self.inner.get(key, kind)).await
+    }
+
+    #[instrument(skip(self), err)]
+    async fn get_range(
+        &self,
+        key: &Location,
+        offset: u64,
+        len: u64,
+        kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        self.retry(|| self.inner.get_range(key, offset, len, kind))
+            .await
+    }
+
+    #[instrument(skip(self), err)]
+    async fn get_batch(
+        &self,
+        keys: &[Location],
+        kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        self.retry(|| self.inner.get_batch(keys, kind)).await
+    }
+}
+
+#[async_trait]
+impl<S: storage::StoragePut> storage::StoragePut for RetryStorage<S> {
+    #[instrument(skip(self, reader), err)]
+    async fn put(
+        &self,
+        mut reader: reader::StreamReadSeeker,
+        len: u64,
+        kind: storage::ObjectKind,
+    ) -> io::Result<Location> {
+        // The inner storage consumes its reader, so every attempt gets its
+        // own cursor over a single copy of the object.
+        let mut buffer = storage::object_buffer(len);
+        reader.read_to_end(&mut buffer).await?;
+        let buffer: Arc<[u8]> = buffer.into();
+
+        self.retry(|| {
+            self.inner
+                .put(Box::new(Cursor::new(buffer.clone())), len, kind)
+        })
+        .await
+    }
+
+    #[instrument(skip(self, objects), err)]
+    async fn put_batch(
+        &self,
+        objects: &[Bytes],
+        kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Location>> {
+        self.retry(|| self.inner.put_batch(objects, kind)).await
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        self.retry(|| self.inner.flush()).await
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageList> storage::StorageList for RetryStorage<S> {
+    #[instrument(skip(self), err)]
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        self.retry(|| self.inner.list()).await
+    }
+}
+
+#[async_trait]
+impl<S: storage::StorageDelete> storage::StorageDelete for RetryStorage<S> {
+    #[instrument(skip(self), err)]
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        self.retry(|| self.inner.delete(key)).await
+    }
+}
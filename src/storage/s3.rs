@@ -0,0 +1,751 @@
+use std::{
+    fmt::{self, Debug},
+    future::Future,
+    io::{self, Cursor, SeekFrom},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use aws_sdk_s3::{
+    Client,
+    error::SdkError,
+    primitives::{ByteStream, ByteStreamError},
+    types::{CompletedMultipartUpload, CompletedPart},
+};
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf},
+    runtime::{Handle, RuntimeFlavor},
+    task::{self, JoinSet},
+};
+use tracing::instrument;
+
+use crate::{location::Location, lock::LockStore, reader, storage};
+
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+/// Requests of a batch sent at once.
+const BATCH_CONCURRENCY: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Prepended to every object key, e.g. `backups/host/`.
+    pub prefix: String,
+    /// Custom endpoint for S3 compatible stores such as MinIO. When unset the
+    /// endpoint is resolved from the environment like any AWS client.
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    /// Address buckets as `endpoint/bucket` instead of `bucket.endpoint`,
+    /// which most self-hosted stores require.
+    pub force_path_style: bool,
+    /// Objects larger than this are sent with a multipart upload.
+    pub multipart_threshold: u64,
+    pub part_size: u64,
+}
+
+impl S3Config {
+    pub fn new<B: Into<String>, P: Into<String>>(bucket: B, prefix: P) -> Self {
+        Self {
+            bucket: bucket.into(),
+            prefix: prefix.into(),
+            endpoint: None,
+            region: None,
+            force_path_style: false,
+            multipart_threshold: 16 * 1024 * 1024,
+            part_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Stores every object under its blake3 hash in an S3 compatible bucket.
+/// Credentials are read from the standard AWS environment variables and
+/// configuration files.
+pub struct S3Storage {
+    client: Client,
+    config: S3Config,
+}
+
+impl Debug for S3Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("S3Storage")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl S3Storage {
+    #[instrument(err)]
+    pub async fn new(config: S3Config) -> io::Result<Self> {
+        if config.part_size < MIN_PART_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("S3 part size must be at least {} bytes", MIN_PART_SIZE),
+            ));
+        }
+
+        let mut loader = aws_config::from_env();
+        if let Some(region) = &config.region {
+            loader = loader.region(aws_config::Region::new(region.clone()));
+        }
+        if let Some(endpoint) = &config.endpoint {
+            loader = loader.endpoint_url(endpoint);
+        }
+        let sdk_config = loader.load().await;
+
+        let s3_config = aws_sdk_s3::config::Builder::from(&sdk_config)
+            .force_path_style(config.force_path_style)
+            .build();
+
+        Ok(Self {
+            client: Client::from_conf(s3_config),
+            config,
+        })
+    }
+
+    fn object_key(&self, key: &Location) -> io::Result<String> {
+        let name = blake3::Hash::from_bytes(key.to_hash()?).to_hex();
+
+        Ok(format!(
+            "{}data/{}/{}",
+            self.config.prefix,
+            &name[0..2],
+            name
+        ))
+    }
+
+    async fn object_length(&self, object_key: &str) -> io::Result<u64> {
+        let head = self
+            .client
+            .head_object()
+            .bucket(&self.config.bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(sdk_error)?;
+
+        Ok(head.content_length().unwrap_or(0).max(0) as u64)
+    }
+
+    fn lock_key(&self, name: &str) -> String {
+        format!("{}locks/{}", self.config.prefix, name)
+    }
+
+    async fn put_multipart(
+        &self,
+        object_key: &str,
+        reader: &mut reader::StreamReadSeeker,
+    ) -> io::Result<()> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.config.bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(sdk_error)?;
+        let upload_id = upload
+            .upload_id()
+            .ok_or_else(|| io::Error::other("S3 did not return an upload id"))?;
+
+        let result = self.upload_parts(object_key, upload_id, reader).await;
+
+        if result.is_err() {
+            let _ = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.config.bucket)
+                .key(object_key)
+                .upload_id(upload_id)
+                .send()
+                .await;
+        }
+
+        result
+    }
+
+    async fn upload_parts(
+        &self,
+        object_key: &str,
+        upload_id: &str,
+        reader: &mut reader::StreamReadSeeker,
+    ) -> io::Result<()> {
+        let mut parts = Vec::new();
+
+        loop {
+            let mut buffer = Vec::with_capacity(self.config.part_size as usize);
+            let n = (&mut *reader)
+                .take(self.config.part_size)
+                .read_to_end(&mut buffer)
+                .await?;
+            if n == 0 && !parts.is_empty() {
+                break;
+            }
+
+            let part_number = parts.len() as i32 + 1;
+            let part = self
+                .client
+                .upload_part()
+                .bucket(&self.config.bucket)
+                .key(object_key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(ByteStream::from(buffer))
+                .send()
+                .await
+                .map_err(sdk_error)?;
+
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(part.e_tag().map(String::from))
+                    .part_number(part_number)
+                    .build(),
+            );
+
+            if (n as u64) < self.config.part_size {
+                break;
+            }
+        }
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.config.bucket)
+            .key(object_key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(sdk_error)?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl storage::StorageGet for S3Storage {
+    #[instrument(err)]
+    async fn get(
+        &self,
+        key: &Location,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let object_key = self.object_key(key)?;
+        let length = self.object_length(&object_key).await?;
+
+        Ok(Box::new(S3Reader::new(
+            self.client.clone(),
+            self.config.bucket.clone(),
+            object_key,
+            0,
+            length,
+        )))
+    }
+
+    /// Checks the range against the length of the object first, like the
+    /// other backends, then requests only the range.
+    #[instrument(err)]
+    async fn get_range(
+        &self,
+        key: &Location,
+        offset: u64,
+        len: u64,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<reader::StreamReadSeeker> {
+        let object_key = self.object_key(key)?;
+        let length = self.object_length(&object_key).await?;
+        storage::check_range(length, offset, len)?;
+
+        Ok(Box::new(S3Reader::new(
+            self.client.clone(),
+            self.config.bucket.clone(),
+            object_key,
+            offset,
+            len,
+        )))
+    }
+
+    /// Sends up to [`BATCH_CONCURRENCY`] requests at once.
+    #[instrument(skip(self, keys), err)]
+    async fn get_batch(
+        &self,
+        keys: &[Location],
+        _kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let object_keys = keys
+            .iter()
+            .map(|v| self.object_key(v))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        concurrently(object_keys, |object_key| {
+            let request = self
+                .client
+                .get_object()
+                .bucket(&self.config.bucket)
+                .key(object_key);
+
+            async move {
+                let object = request.send().await.map_err(sdk_error)?;
+
+                let buffer = object.body.collect().await.map_err(body_error)?;
+
+                Ok(buffer.to_vec())
+            }
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl storage::StoragePut for S3Storage {
+    #[instrument(skip(reader), ret, err)]
+    async fn put(
+        &self,
+        mut reader: reader::StreamReadSeeker,
+        len: u64,
+        _kind: storage::ObjectKind,
+    ) -> io::Result<Location> {
+        let hash = {
+            let mut hasher = blake3::Hasher::new();
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let n = reader.read(&mut buffer).await?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..n]);
+            }
+
+            hasher.finalize()
+        };
+
+        let key = Location::hash(hash.as_bytes());
+        let object_key = self.object_key(&key)?;
+
+        if object_exists(&self.client, &self.config.bucket, &object_key).await? {
+            return Ok(key);
+        }
+
+        reader.seek(SeekFrom::Start(0)).await?;
+
+        if len > self.config.multipart_threshold {
+            self.put_multipart(&object_key, &mut reader).await?;
+        } else {
+            let mut buffer = storage::object_buffer(len);
+            reader.read_to_end(&mut buffer).await?;
+
+            self.client
+                .put_object()
+                .bucket(&self.config.bucket)
+                .key(&object_key)
+                .body(ByteStream::from(buffer))
+                .send()
+                .await
+                .map_err(sdk_error)?;
+        }
+
+        Ok(key)
+    }
+
+    /// Uploads objects small enough for a single request with up to
+    /// [`BATCH_CONCURRENCY`] requests at once, and larger ones one by one.
+    #[instrument(skip(self, objects), err)]
+    async fn put_batch(
+        &self,
+        objects: &[Bytes],
+        kind: storage::ObjectKind,
+    ) -> io::Result<Vec<Location>> {
+        let mut keys = Vec::with_capacity(objects.len());
+        let mut uploads = Vec::new();
+
+        for object in objects {
+            let len = object.len() as u64;
+            if len > self.config.multipart_threshold {
+                keys.push(
+                    self.put(Box::new(Cursor::new(object.clone())), len, kind)
+                        .await?,
+                );
+                continue;
+            }
+
+            let key = Location::hash(blake3::hash(object).as_bytes());
+            uploads.push((self.object_key(&key)?, object.clone()));
+            keys.push(key);
+        }
+
+        concurrently(uploads, |(object_key, object)| {
+            let client = self.client.clone();
+            let bucket = self.config.bucket.clone();
+
+            async move {
+                if object_exists(&client, &bucket, &object_key).await? {
+                    return Ok(());
+                }
+
+                client
+                    .put_object()
+                    .bucket(bucket)
+                    .key(object_key)
+                    .body(ByteStream::from(object))
+                    .send()
+                    .await
+                    .map_err(sdk_error)?;
+
+                Ok(())
+            }
+        })
+        .await?;
+
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl storage::StorageList for S3Storage {
+    #[instrument(err)]
+    async fn list(&self) -> io::Result<Vec<Location>> {
+        let mut keys = Vec::new();
+
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.config.bucket)
+            .prefix(format!("{}data/", self.config.prefix))
+            .into_paginator()
+            .send();
+
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(sdk_error)?;
+
+            for object in page.contents() {
+                let hash = object
+                    .key()
+                    .and_then(|v| v.rsplit('/').next())
+                    .and_then(|v| blake3::Hash::from_hex(v).ok());
+
+                if let Some(hash) = hash {
+                    keys.push(Location::hash(hash.as_bytes()));
+                }
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+#[async_trait]
+impl storage::StorageDelete for S3Storage {
+    #[instrument(err)]
+    async fn delete(&self, key: &Location) -> io::Result<()> {
+        let object_key = self.object_key(key)?;
+
+        self.client
+            .delete_object()
+            .bucket(&self.config.bucket)
+            .key(object_key)
+            .send()
+            .await
+            .map_err(sdk_error)?;
+
+        Ok(())
+    }
+}
+
+/// Keeps locks as objects under `locks/` of the prefix. A put is atomic, so
+/// other processes see a lock whole or not at all.
+#[async_trait]
+impl LockStore for S3Storage {
+    async fn create(&self, name: &str, buffer: &[u8]) -> io::Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.config.bucket)
+            .key(self.lock_key(name))
+            .body(ByteStream::from(buffer.to_vec()))
+            .send()
+            .await
+            .map_err(sdk_error)?;
+
+        Ok(())
+    }
+
+    async fn list(&self) -> io::Result<Vec<(String, Vec<u8>)>> {
+        let prefix = self.lock_key("");
+        let mut names = Vec::new();
+
+        let mut pages = self
+            .client
+            .list_objects_v2()
+            .bucket(&self.config.bucket)
+            .prefix(&prefix)
+            .into_paginator()
+            .send();
+
+        while let Some(page) = pages.next().await {
+            let page = page.map_err(sdk_error)?;
+            names.extend(
+                page.contents()
+                    .iter()
+                    .filter_map(|v| v.key()?.strip_prefix(&prefix))
+                    .map(str::to_string),
+            );
+        }
+
+        let mut locks = Vec::new();
+        for name in names {
+            let result = self
+                .client
+                .get_object()
+                .bucket(&self.config.bucket)
+                .key(self.lock_key(&name))
+                .send()
+                .await;
+
+            let object = match result {
+                Ok(v) => v,
+                // Released while listing.
+                Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => continue,
+                Err(e) => return Err(sdk_error(e)),
+            };
+
+            let buffer = object.body.collect().await.map_err(body_error)?.to_vec();
+            locks.push((name, buffer));
+        }
+
+        Ok(locks)
+    }
+
+    async fn remove(&self, name: &str) -> io::Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.config.bucket)
+            .key(self.lock_key(name))
+            .send()
+            .await
+            .map_err(sdk_error)?;
+
+        Ok(())
+    }
+
+    /// Blocks the worker thread on the request, which needs the
+    /// multi-threaded runtime.
+    fn remove_blocking(&self, name: &str) -> io::Result<()> {
+        let handle = Handle::try_current().map_err(io::Error::other)?;
+        if handle.runtime_flavor() != RuntimeFlavor::MultiThread {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "releasing an S3 lock needs the multi-threaded runtime",
+            ));
+        }
+
+        task::block_in_place(|| handle.block_on(self.remove(name)))
+    }
+}
+
+async fn object_exists(client: &Client, bucket: &str, object_key: &str) -> io::Result<bool> {
+    let result = client
+        .head_object()
+        .bucket(bucket)
+        .key(object_key)
+        .send()
+        .await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+        Err(e) => Err(sdk_error(e)),
+    }
+}
+
+/// Maps a failed request to the kind of error callers act on: missing objects
+/// to `NotFound`, and throttling, server errors, timeouts and dropped
+/// connections to kinds [`RetryStorage`](storage::RetryStorage) retries.
+fn sdk_error<E: std::error::Error + Send + Sync + 'static>(error: SdkError<E>) -> io::Error {
+    let kind = match &error {
+        SdkError::TimeoutError(_) => io::ErrorKind::TimedOut,
+        SdkError::DispatchFailure(v) if v.is_timeout() => io::ErrorKind::TimedOut,
+        SdkError::DispatchFailure(v) if v.is_io() => io::ErrorKind::ConnectionReset,
+        SdkError::ResponseError(_) => io::ErrorKind::ConnectionAborted,
+        SdkError::ServiceError(v) => match v.raw().status().as_u16() {
+            404 => io::ErrorKind::NotFound,
+            403 => io::ErrorKind::PermissionDenied,
+            429 | 500..=599 => io::ErrorKind::ResourceBusy,
+            _ => io::ErrorKind::Other,
+        },
+        _ => io::ErrorKind::Other,
+    };
+
+    io::Error::new(kind, error)
+}
+
+/// A body cut off while it was collected, worth requesting again.
+fn body_error(error: ByteStreamError) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, error)
+}
+
+/// Runs `request` for every item with up to [`BATCH_CONCURRENCY`] of them in
+/// flight, returning the results in the order of `items`. The first failure
+/// cancels the requests still running.
+async fn concurrently<T, R, F, Fut>(items: Vec<T>, request: F) -> io::Result<Vec<R>>
+where
+    R: Send + 'static,
+    F: Fn(T) -> Fut,
+    Fut: Future<Output = io::Result<R>> + Send + 'static,
+{
+    let mut results: Vec<Option<R>> = std::iter::repeat_with(|| None).take(items.len()).collect();
+    let mut tasks = JoinSet::new();
+
+    for (index, item) in items.into_iter().enumerate() {
+        if tasks.len() >= BATCH_CONCURRENCY
+            && let Some(joined) = tasks.join_next().await
+        {
+            let (index, result) = joined.map_err(io::Error::other)?;
+            results[index] = Some(result?);
+        }
+
+        let future = request(item);
+        tasks.spawn(async move { (index, future.await) });
+    }
+
+    while let Some(joined) = tasks.join_next().await {
+        let (index, result) = joined.map_err(io::Error::other)?;
+        results[index] = Some(result?);
+    }
+
+    Ok(results
+        .into_iter()
+        .map(|v| v.expect("every request completed"))
+        .collect())
+}
+
+type BodyReader = Pin<Box<dyn AsyncRead + Send>>;
+type BodyFuture = Pin<Box<dyn Future<Output = io::Result<BodyReader>> + Send>>;
+
+enum ReaderState {
+    Idle,
+    Requesting(BodyFuture),
+    Reading(BodyReader),
+}
+
+/// Reads an object through ranged GET requests. Seeking drops the current
+/// response and the next read requests the object from the new position.
+struct S3Reader {
+    client: Client,
+    bucket: String,
+    key: String,
+    /// Offset in the object of the first byte read.
+    start: u64,
+    length: u64,
+    position: u64,
+    state: ReaderState,
+}
+
+impl S3Reader {
+    fn new(client: Client, bucket: String, key: String, start: u64, length: u64) -> Self {
+        Self {
+            client,
+            bucket,
+            key,
+            start,
+            length,
+            position: 0,
+            state: ReaderState::Idle,
+        }
+    }
+
+    fn request(&self) -> BodyFuture {
+        let request = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .range(format!(
+                "bytes={}-{}",
+                self.start + self.position,
+                self.start + self.length - 1
+            ));
+
+        Box::pin(async move {
+            let output = request.send().await.map_err(sdk_error)?;
+            let body: BodyReader = Box::pin(output.body.into_async_read());
+
+            Ok(body)
+        })
+    }
+}
+
+impl AsyncRead for S3Reader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if self.position >= self.length {
+                return Poll::Ready(Ok(()));
+            }
+
+            match &mut self.state {
+                ReaderState::Idle => {
+                    let future = self.request();
+                    self.state = ReaderState::Requesting(future);
+                }
+                ReaderState::Requesting(future) => match future.as_mut().poll(cx) {
+                    Poll::Ready(Ok(body)) => self.state = ReaderState::Reading(body),
+                    Poll::Ready(Err(e)) => {
+                        self.state = ReaderState::Idle;
+                        return Poll::Ready(Err(e));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                ReaderState::Reading(body) => {
+                    let before = buf.filled().len();
+                    return match body.as_mut().poll_read(cx, buf) {
+                        Poll::Ready(Ok(())) => {
+                            let read = buf.filled().len() - before;
+                            // A body ending early, e.g. on a dropped
+                            // connection, must not pass for the end of the
+                            // object.
+                            if read == 0 && buf.remaining() > 0 {
+                                return Poll::Ready(Err(io::Error::new(
+                                    io::ErrorKind::UnexpectedEof,
+                                    format!(
+                                        "object {} ended after {} of {} bytes",
+                                        self.key, self.position, self.length
+                                    ),
+                                )));
+                            }
+                            self.position += read as u64;
+                            Poll::Ready(Ok(()))
+                        }
+                        other => other,
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for S3Reader {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let new_position = match position {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+            SeekFrom::End(n) => self.length.checked_add_signed(n),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start"))?;
+
+        if new_position != self.position {
+            self.position = new_position;
+            self.state = ReaderState::Idle;
+        }
+
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
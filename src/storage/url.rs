@@ -0,0 +1,276 @@
+use std::{
+    fmt::{self, Display},
+    io,
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::{
+    lock::{DirectoryLocks, LockStore},
+    storage::{self, ManagedStorage, StorageGet, SyncPolicy},
+};
+
+const LOCKS_EXTENSION: &str = "locks";
+const LOCKS_DIR: &str = "locks";
+
+/// Location of a repository's storage, written as a URL:
+///
+/// - `file:///path/repo.bin`, a single blob file;
+/// - `dir:///path`, one file per object;
+/// - `pack:///path`, objects grouped in pack files;
+/// - `s3://bucket/prefix`, optionally followed by `?endpoint=...`,
+///   `region=...` and `path_style=true`;
+/// - `lepatch+ssh://[user@]host[:port]/path`, a blob file on a remote host,
+///   served by the `serve` command through ssh.
+///
+/// Everything after `://` is the path, so `file://repo.bin` is relative to
+/// the working directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StorageUrl {
+    File(PathBuf),
+    Directory(PathBuf),
+    Pack(PathBuf),
+    S3 {
+        bucket: String,
+        prefix: String,
+        endpoint: Option<String>,
+        region: Option<String>,
+        force_path_style: bool,
+    },
+    Ssh {
+        host: String,
+        port: Option<u16>,
+        path: PathBuf,
+    },
+}
+
+fn invalid_url<D: Display>(url: &str, reason: D) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Invalid storage URL {}: {}", url, reason),
+    )
+}
+
+impl FromStr for StorageUrl {
+    type Err = io::Error;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| invalid_url(url, "missing scheme"))?;
+
+        if rest.is_empty() {
+            return Err(invalid_url(url, "missing path"));
+        }
+
+        match scheme {
+            "file" => Ok(Self::File(rest.into())),
+            "dir" => Ok(Self::Directory(rest.into())),
+            "pack" => Ok(Self::Pack(rest.into())),
+            "s3" => {
+                let (location, query) = rest.split_once('?').unwrap_or((rest, ""));
+                let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+
+                if bucket.is_empty() {
+                    return Err(invalid_url(url, "missing bucket"));
+                }
+
+                let mut prefix = prefix.trim_end_matches('/').to_string();
+                if !prefix.is_empty() {
+                    prefix.push('/');
+                }
+
+                let mut endpoint = None;
+                let mut region = None;
+                let mut force_path_style = false;
+
+                for pair in query.split('&').filter(|v| !v.is_empty()) {
+                    match pair.split_once('=') {
+                        Some(("endpoint", v)) => endpoint = Some(v.to_string()),
+                        Some(("region", v)) => region = Some(v.to_string()),
+                        Some(("path_style", v)) => {
+                            force_path_style = v
+                                .parse()
+                                .map_err(|e| invalid_url(url, format!("path_style: {}", e)))?
+                        }
+                        _ => return Err(invalid_url(url, format!("unknown option {}", pair))),
+                    }
+                }
+
+                Ok(Self::S3 {
+                    bucket: bucket.to_string(),
+                    prefix,
+                    endpoint,
+                    region,
+                    force_path_style,
+                })
+            }
+            "lepatch+ssh" => {
+                let (authority, path) = rest
+                    .split_once('/')
+                    .ok_or_else(|| invalid_url(url, "missing path"))?;
+
+                let (host, port) = match authority.rsplit_once(':') {
+                    Some((host, port)) => {
+                        let port = port
+                            .parse()
+                            .map_err(|e| invalid_url(url, format!("port: {}", e)))?;
+                        (host, Some(port))
+                    }
+                    None => (authority, None),
+                };
+
+                if host.is_empty() || path.is_empty() {
+                    return Err(invalid_url(url, "missing host or path"));
+                }
+
+                Ok(Self::Ssh {
+                    host: host.to_string(),
+                    port,
+                    path: PathBuf::from("/").join(path),
+                })
+            }
+            _ => Err(invalid_url(url, format!("unknown scheme {}", scheme))),
+        }
+    }
+}
+
+impl Display for StorageUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "file://{}", path.display()),
+            Self::Directory(path) => write!(f, "dir://{}", path.display()),
+            Self::Pack(path) => write!(f, "pack://{}", path.display()),
+            Self::S3 {
+                bucket,
+                prefix,
+                endpoint,
+                region,
+                force_path_style,
+            } => {
+                write!(f, "s3://{}/{}", bucket, prefix)?;
+
+                let options = [
+                    endpoint.as_ref().map(|v| format!("endpoint={}", v)),
+                    region.as_ref().map(|v| format!("region={}", v)),
+                    force_path_style.then(|| "path_style=true".to_string()),
+                ];
+                let options: Vec<_> = options.into_iter().flatten().collect();
+                if !options.is_empty() {
+                    write!(f, "?{}", options.join("&"))?;
+                }
+
+                Ok(())
+            }
+            Self::Ssh { host, port, path } => {
+                write!(f, "lepatch+ssh://{}", host)?;
+                if let Some(port) = port {
+                    write!(f, ":{}", port)?;
+                }
+                write!(f, "{}", path.display())
+            }
+        }
+    }
+}
+
+impl StorageUrl {
+    /// Opens the storage for reading and writing, creating it if needed.
+    /// `sync_policy` applies to local files; a remote host uses its default.
+    pub async fn open(&self, sync_policy: SyncPolicy) -> io::Result<Box<dyn ManagedStorage>> {
+        let storage: Box<dyn ManagedStorage> = match self {
+            Self::File(path) => Box::new(
+                storage::BlobFileStorage::<true>::new(path)
+                    .await?
+                    .sync_policy(sync_policy),
+            ),
+            Self::Directory(path) => Box::new(storage::DirectoryStorage::<true>::new(path).await?),
+            Self::Pack(path) => Box::new(
+                storage::PackStorage::<true>::new(path)
+                    .await?
+                    .sync_policy(sync_policy),
+            ),
+            #[cfg(feature = "s3")]
+            Self::S3 { .. } => Box::new(self.open_s3().await?),
+            #[cfg(not(feature = "s3"))]
+            Self::S3 { .. } => return Err(s3_unsupported()),
+            Self::Ssh { host, port, path } => Box::new(
+                storage::RemoteStorage::connect_ssh(host, *port, &Self::File(path.clone()), true)
+                    .await?,
+            ),
+        };
+
+        Ok(storage)
+    }
+
+    /// Opens an existing storage for reading only. A remote host is asked for
+    /// a shared lock instead of an exclusive one.
+    pub async fn open_read(&self) -> io::Result<Box<dyn StorageGet>> {
+        let storage: Box<dyn StorageGet> = match self {
+            Self::File(path) => Box::new(storage::BlobFileStorage::<false>::new(path).await?),
+            Self::Directory(path) => Box::new(storage::DirectoryStorage::<false>::new(path).await?),
+            Self::Pack(path) => Box::new(storage::PackStorage::<false>::new(path).await?),
+            #[cfg(feature = "s3")]
+            Self::S3 { .. } => Box::new(self.open_s3().await?),
+            #[cfg(not(feature = "s3"))]
+            Self::S3 { .. } => return Err(s3_unsupported()),
+            Self::Ssh { host, port, path } => Box::new(
+                storage::RemoteStorage::connect_ssh(host, *port, &Self::File(path.clone()), false)
+                    .await?,
+            ),
+        };
+
+        Ok(storage)
+    }
+
+    /// Opens where the locks of the repository are kept: beside a blob file,
+    /// inside a directory or pack repository and under the prefix of a
+    /// bucket. A repository reached through ssh is locked by the `serve`
+    /// command on its host for as long as the connection is open, so it has
+    /// none here.
+    pub async fn locks(&self) -> io::Result<Option<Box<dyn LockStore>>> {
+        let locks: Box<dyn LockStore> = match self {
+            Self::File(path) => Box::new(DirectoryLocks::new(path.with_extension(LOCKS_EXTENSION))),
+            Self::Directory(path) | Self::Pack(path) => {
+                Box::new(DirectoryLocks::new(path.join(LOCKS_DIR)))
+            }
+            #[cfg(feature = "s3")]
+            Self::S3 { .. } => Box::new(self.open_s3().await?),
+            #[cfg(not(feature = "s3"))]
+            Self::S3 { .. } => return Err(s3_unsupported()),
+            Self::Ssh { .. } => return Ok(None),
+        };
+
+        Ok(Some(locks))
+    }
+
+    #[cfg(feature = "s3")]
+    async fn open_s3(&self) -> io::Result<storage::S3Storage> {
+        let Self::S3 {
+            bucket,
+            prefix,
+            endpoint,
+            region,
+            force_path_style,
+        } = self
+        else {
+            unreachable!("open_s3 is only called for s3 URLs");
+        };
+
+        let config = storage::S3Config {
+            endpoint: endpoint.clone(),
+            region: region.clone(),
+            force_path_style: *force_path_style,
+            ..storage::S3Config::new(bucket, prefix)
+        };
+
+        storage::S3Storage::new(config).await
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+fn s3_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "S3 storage requires building with the s3 feature",
+    )
+}
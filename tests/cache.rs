@@ -0,0 +1,111 @@
+//! Checks that `CachedStorage` only serves bytes it can vouch for.
+
+use std::{fs, io::Cursor};
+
+use lepatch::{
+    location::Location,
+    storage::{CachedStorage, InMemoryStorage, ObjectKind, StorageDelete, StorageGet, StoragePut},
+};
+use tokio::io::AsyncReadExt;
+
+async fn read<S: StorageGet>(storage: &S, key: &Location) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    storage
+        .get(key, ObjectKind::Chunk)
+        .await
+        .unwrap()
+        .read_to_end(&mut buffer)
+        .await
+        .unwrap();
+    buffer
+}
+
+async fn put<S: StoragePut>(storage: &S, buffer: &[u8]) -> Location {
+    let reader = Box::new(Cursor::new(buffer.to_vec()));
+    storage
+        .put(reader, buffer.len() as u64, ObjectKind::Chunk)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn corrupt_cache_file_falls_back_to_inner() {
+    let dir = tempfile::tempdir().unwrap();
+    let inner = InMemoryStorage::new();
+    let cache = CachedStorage::new(inner.clone(), dir.path(), 1024 * 1024)
+        .await
+        .unwrap();
+
+    let key = put(&cache, b"original object").await;
+
+    for entry in fs::read_dir(dir.path()).unwrap() {
+        let path = entry.unwrap().path();
+        let mut buffer = fs::read(&path).unwrap();
+        let last = buffer.len() - 1;
+        buffer[last] ^= 0xff;
+        fs::write(&path, buffer).unwrap();
+    }
+
+    assert_eq!(read(&cache, &key).await, b"original object");
+}
+
+#[tokio::test]
+async fn namespaces_do_not_share_objects() {
+    let dir = tempfile::tempdir().unwrap();
+    let first = InMemoryStorage::new();
+    let first_cache = CachedStorage::new(first.clone(), dir.path(), 1024 * 1024)
+        .await
+        .unwrap()
+        .namespace("first");
+    let second_cache = CachedStorage::new(InMemoryStorage::new(), dir.path(), 1024 * 1024)
+        .await
+        .unwrap()
+        .namespace("second");
+
+    let key = put(&first_cache, b"only in the first repository").await;
+    assert_eq!(
+        read(&first_cache, &key).await,
+        b"only in the first repository"
+    );
+
+    assert!(second_cache.get(&key, ObjectKind::Chunk).await.is_err());
+}
+
+#[tokio::test]
+async fn evicts_least_recently_used() {
+    let dir = tempfile::tempdir().unwrap();
+    let inner = InMemoryStorage::new();
+    // Room for two objects of 100 bytes plus their hash header.
+    let cache = CachedStorage::new(inner.clone(), dir.path(), 300)
+        .await
+        .unwrap();
+
+    let a = put(&cache, &[1; 100]).await;
+    let b = put(&cache, &[2; 100]).await;
+    read(&cache, &a).await;
+    let c = put(&cache, &[3; 100]).await;
+
+    for key in [&a, &b, &c] {
+        inner.delete(key).await.unwrap();
+    }
+
+    assert_eq!(read(&cache, &a).await, [1; 100]);
+    assert!(cache.get(&b, ObjectKind::Chunk).await.is_err());
+    assert_eq!(read(&cache, &c).await, [3; 100]);
+}
+
+#[tokio::test]
+async fn deletes_objects_evicted_from_the_cache() {
+    let dir = tempfile::tempdir().unwrap();
+    let inner = InMemoryStorage::new();
+    let cache = CachedStorage::new(inner.clone(), dir.path(), 300)
+        .await
+        .unwrap();
+
+    let a = put(&cache, &[1; 100]).await;
+    put(&cache, &[2; 100]).await;
+    put(&cache, &[3; 100]).await;
+
+    cache.delete(&a).await.unwrap();
+    assert!(inner.get(&a, ObjectKind::Chunk).await.is_err());
+}
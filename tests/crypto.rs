@@ -0,0 +1,106 @@
+//! Checks that sealed objects only open with the key and kind they were
+//! sealed with, and that malformed key material is rejected.
+
+use std::io;
+
+use lepatch::{
+    crypto::{EncryptionConfig, MasterKey, RepositoryKey},
+    storage::ObjectKind,
+};
+
+#[test]
+fn open_fails_for_another_kind() {
+    let key = MasterKey::generate();
+    let sealed = key.seal(b"content", ObjectKind::Chunk).unwrap();
+
+    assert_eq!(key.open(&sealed, ObjectKind::Chunk).unwrap(), b"content");
+    let e = key.open(&sealed, ObjectKind::Snapshot).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn open_fails_with_another_key() {
+    let key = MasterKey::generate();
+    let sealed = key.seal(b"content", ObjectKind::Chunk).unwrap();
+
+    let e = MasterKey::generate()
+        .open(&sealed, ObjectKind::Chunk)
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn write_only_open_fails_with_another_key_or_kind() {
+    let key = MasterKey::generate();
+    let other = MasterKey::generate();
+    let sealed = key
+        .public_key()
+        .seal(b"content", ObjectKind::Snapshot)
+        .unwrap();
+
+    let write_only = |master_key| RepositoryKey::WriteOnly {
+        public_key: key.public_key(),
+        chunk_id_key: key.chunk_id_key(),
+        master_key: Some(master_key),
+    };
+
+    let opened = write_only(key.clone())
+        .open(&sealed, ObjectKind::Snapshot)
+        .unwrap();
+    assert_eq!(opened, b"content");
+
+    let e = write_only(key.clone())
+        .open(&sealed, ObjectKind::Chunk)
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+    let e = write_only(other)
+        .open(&sealed, ObjectKind::Snapshot)
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn rejects_non_hex_key_material() {
+    for text in ["\"zz\"", "\"a\"", "\"\u{e9}\u{e9}\""] {
+        let json = format!("{{\"chunk_id_key\":{}}}", text);
+        assert!(serde_json::from_str::<EncryptionConfig>(&json).is_err());
+    }
+
+    let json = format!("{{\"chunk_id_key\":\"{}\"}}", "ab".repeat(32));
+    let config: EncryptionConfig = serde_json::from_str(&json).unwrap();
+    assert_eq!(config.chunk_id_key.unwrap().as_bytes(), &[0xab; 32]);
+}
+
+#[test]
+fn keys_are_added_changed_and_removed() {
+    let key = MasterKey::generate();
+    let mut config = EncryptionConfig::new(&key, b"first", None, false).unwrap();
+
+    let second = config
+        .add(&key, b"second", Some("laptop".into()))
+        .unwrap()
+        .id
+        .clone();
+    assert_eq!(config.unlock_slot(b"second").unwrap().0, 1);
+
+    config.change_password(b"second", b"third").unwrap();
+    let e = config.unlock(b"second").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    let unlocked = config.unlock(b"third").unwrap();
+    assert_eq!(unlocked.chunk_id_key(), key.chunk_id_key());
+
+    // Survives being stored in the repository config.
+    let mut config: EncryptionConfig =
+        serde_json::from_slice(&serde_json::to_vec(&config).unwrap()).unwrap();
+
+    let first = config.slots[0].id.clone();
+    config.remove(&first).unwrap();
+    let e = config.unlock(b"first").unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+    config.unlock(b"third").unwrap();
+
+    let e = config.remove(&second).unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(config.slots.len(), 1);
+}
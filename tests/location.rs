@@ -0,0 +1,68 @@
+//! Parses locations from their binary and text forms, current and legacy.
+
+use std::io;
+
+use lepatch::location::Location;
+
+fn hash(seed: u8) -> [u8; 32] {
+    *blake3::hash(&[seed]).as_bytes()
+}
+
+#[test]
+fn binary_round_trips() {
+    let blob = Location::blob(1 << 40, 12345);
+    let hashed = Location::hash(&hash(1));
+    let mirror = Location::mirror(&hash(2), &[blob.clone(), hashed.clone()]);
+
+    for location in [&blob, &hashed, &mirror] {
+        assert_eq!(
+            &Location::from_bytes(location.as_bytes().to_vec()).unwrap(),
+            location
+        );
+        assert_eq!(&location.to_string().parse::<Location>().unwrap(), location);
+    }
+
+    assert_eq!(blob.to_blob().unwrap(), (1 << 40, 12345));
+    assert_eq!(hashed.to_hash().unwrap(), hash(1));
+    assert_eq!(mirror.to_mirror().unwrap(), (hash(2), vec![blob, hashed]));
+}
+
+#[test]
+fn legacy_json_is_converted() {
+    let blob: Location = r#"{"offset":10,"length":20}"#.parse().unwrap();
+    assert_eq!(blob, Location::blob(10, 20));
+
+    let bytes = br#"{"offset":10,"length":20}"#.to_vec();
+    assert_eq!(Location::from_bytes(bytes).unwrap(), blob);
+
+    let text = format!(
+        r#"{{"hash":"{}","keys":["{}",{}]}}"#,
+        blake3::Hash::from_bytes(hash(3)).to_hex(),
+        blake3::Hash::from_bytes(hash(4)).to_hex(),
+        serde_json::to_string(r#"{"offset":1,"length":2}"#).unwrap(),
+    );
+    let mirror: Location = text.parse().unwrap();
+    assert_eq!(
+        mirror,
+        Location::mirror(&hash(3), &[Location::hash(&hash(4)), Location::blob(1, 2)])
+    );
+}
+
+#[test]
+fn legacy_hex_is_converted() {
+    let text = blake3::Hash::from_bytes(hash(5)).to_hex().to_string();
+
+    assert_eq!(text.parse::<Location>().unwrap(), Location::hash(&hash(5)));
+    assert_eq!(
+        Location::from_bytes(text.into_bytes()).unwrap(),
+        Location::hash(&hash(5))
+    );
+}
+
+#[test]
+fn rejects_text_that_is_not_hex() {
+    for text in ["0é", "é0", "01\u{2603}", "zz"] {
+        let error = text.parse::<Location>().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", text);
+    }
+}
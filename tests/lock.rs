@@ -0,0 +1,37 @@
+//! Checks how `RepositoryLock` handles lock files it cannot parse.
+
+use std::{fs, io};
+
+use lepatch::lock::{DirectoryLocks, LockEntry, RepositoryLock};
+
+#[tokio::test]
+async fn malformed_lock_conflicts_until_unlocked() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("0123456789abcdef.lock"), b"{\"pid\":").unwrap();
+
+    let locks = RepositoryLock::list(&DirectoryLocks::new(dir.path()))
+        .await
+        .unwrap();
+    assert_eq!(locks.len(), 1);
+    assert!(matches!(locks[0].1, LockEntry::Malformed(_)));
+
+    let e = RepositoryLock::shared(Box::new(DirectoryLocks::new(dir.path())))
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::ResourceBusy);
+
+    // Without `--all` nothing tells whether its holder still runs.
+    let removed = RepositoryLock::unlock(&DirectoryLocks::new(dir.path()), false)
+        .await
+        .unwrap();
+    assert!(removed.is_empty());
+
+    let removed = RepositoryLock::unlock(&DirectoryLocks::new(dir.path()), true)
+        .await
+        .unwrap();
+    assert!(matches!(removed[..], [LockEntry::Malformed(_)]));
+
+    RepositoryLock::exclusive(Box::new(DirectoryLocks::new(dir.path())))
+        .await
+        .unwrap();
+}
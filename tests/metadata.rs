@@ -0,0 +1,160 @@
+//! Decodes snapshots in the current tagged layout and in every layout
+//! written before snapshots were tagged.
+
+use std::path::PathBuf;
+
+use lepatch::{
+    location::Location,
+    metadata::{Chunk, File, FileChunk, FileStat, FileSymlink, Snapshot},
+};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct ChunkV0 {
+    hash: [u8; 32],
+    location: Location,
+}
+
+#[derive(Serialize)]
+struct FileV0 {
+    path: PathBuf,
+}
+
+#[derive(Serialize)]
+struct SnapshotV1 {
+    files: Vec<File>,
+    chunks: Vec<ChunkV0>,
+    file_chunks: Vec<FileChunk>,
+    file_symlink: Vec<FileSymlink>,
+    incomplete: bool,
+}
+
+#[derive(Serialize)]
+struct SnapshotV0 {
+    files: Vec<FileV0>,
+    chunks: Vec<ChunkV0>,
+    file_chunks: Vec<FileChunk>,
+    file_symlink: Vec<FileSymlink>,
+}
+
+fn hash(seed: u8) -> [u8; 32] {
+    *blake3::hash(&[seed]).as_bytes()
+}
+
+fn file() -> File {
+    File {
+        path: "dir/file".into(),
+        stat: Some(FileStat {
+            size: 7,
+            modified: None,
+            id: None,
+        }),
+    }
+}
+
+fn chunks_v0() -> Vec<ChunkV0> {
+    vec![ChunkV0 {
+        hash: hash(1),
+        location: Location::blob(0, 7),
+    }]
+}
+
+fn file_chunks() -> Vec<FileChunk> {
+    vec![FileChunk {
+        chunk_index: 0,
+        file_index: 0,
+        chunk_offset: 0,
+        file_offset: 0,
+        length: 7,
+    }]
+}
+
+fn symlinks() -> Vec<FileSymlink> {
+    vec![FileSymlink {
+        path: "link".into(),
+        source: "dir/file".into(),
+        is_hard: false,
+    }]
+}
+
+fn snapshot() -> Snapshot {
+    Snapshot {
+        files: vec![file()],
+        chunks: vec![Chunk {
+            hash: hash(1),
+            location: Location::blob(0, 7),
+            checksum: Some(hash(2)),
+        }],
+        file_chunks: file_chunks(),
+        file_symlink: symlinks(),
+        incomplete: true,
+    }
+}
+
+#[test]
+fn tagged_round_trips() {
+    let buffer = snapshot().encode().unwrap();
+    assert!(buffer.starts_with(b"LPS2"));
+
+    let decoded = Snapshot::decode(&buffer).unwrap();
+    assert_eq!(decoded.files[0].path, PathBuf::from("dir/file"));
+    assert_eq!(decoded.chunks[0].checksum, Some(hash(2)));
+    assert_eq!(decoded.file_chunks[0].length, 7);
+    assert_eq!(decoded.file_symlink[0].path, PathBuf::from("link"));
+    assert!(decoded.incomplete);
+}
+
+#[test]
+fn tagged_rejects_truncation() {
+    let buffer = snapshot().encode().unwrap();
+    let e = Snapshot::decode(&buffer[..buffer.len() - 1]).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn decodes_untagged_current_layout() {
+    let buffer = bincode::serialize(&snapshot()).unwrap();
+
+    let decoded = Snapshot::decode(&buffer).unwrap();
+    assert_eq!(decoded.chunks[0].checksum, Some(hash(2)));
+    assert!(decoded.files[0].stat.is_some());
+    assert!(decoded.incomplete);
+}
+
+#[test]
+fn decodes_v1_layout() {
+    let buffer = bincode::serialize(&SnapshotV1 {
+        files: vec![file()],
+        chunks: chunks_v0(),
+        file_chunks: file_chunks(),
+        file_symlink: symlinks(),
+        incomplete: true,
+    })
+    .unwrap();
+
+    let decoded = Snapshot::decode(&buffer).unwrap();
+    assert_eq!(decoded.chunks[0].hash, hash(1));
+    assert_eq!(decoded.chunks[0].checksum, None);
+    assert!(decoded.files[0].stat.is_some());
+    assert!(decoded.incomplete);
+}
+
+#[test]
+fn decodes_v0_layout() {
+    let buffer = bincode::serialize(&SnapshotV0 {
+        files: vec![FileV0 {
+            path: "dir/file".into(),
+        }],
+        chunks: chunks_v0(),
+        file_chunks: file_chunks(),
+        file_symlink: symlinks(),
+    })
+    .unwrap();
+
+    let decoded = Snapshot::decode(&buffer).unwrap();
+    assert_eq!(decoded.files[0].path, PathBuf::from("dir/file"));
+    assert!(decoded.files[0].stat.is_none());
+    assert_eq!(decoded.chunks[0].checksum, None);
+    assert_eq!(decoded.file_symlink[0].source, PathBuf::from("dir/file"));
+    assert!(!decoded.incomplete);
+}
@@ -0,0 +1,52 @@
+//! Talks to `serve` through an in-process pipe.
+
+use std::io::Cursor;
+
+use lepatch::storage::{
+    InMemoryStorage, ObjectKind, RemoteStorage, StorageDelete, StorageList, StoragePut,
+    is_transient, serve,
+};
+use tokio::task::JoinHandle;
+
+async fn connect(storage: InMemoryStorage) -> (RemoteStorage, JoinHandle<std::io::Result<()>>) {
+    let (client, server) = tokio::io::duplex(64 * 1024);
+    let (server_reader, server_writer) = tokio::io::split(server);
+    let server = tokio::spawn(async move { serve(&storage, server_reader, server_writer).await });
+
+    let (client_reader, client_writer) = tokio::io::split(client);
+    let remote = RemoteStorage::new(client_reader, client_writer)
+        .await
+        .unwrap();
+
+    (remote, server)
+}
+
+#[tokio::test]
+async fn list_and_delete() {
+    let storage = InMemoryStorage::new();
+    let (remote, _server) = connect(storage.clone()).await;
+
+    let key = remote
+        .put(
+            Box::new(Cursor::new(b"object".to_vec())),
+            6,
+            ObjectKind::Chunk,
+        )
+        .await
+        .unwrap();
+    assert_eq!(remote.list().await.unwrap(), vec![key.clone()]);
+
+    remote.delete(&key).await.unwrap();
+    assert!(remote.list().await.unwrap().is_empty());
+    assert_eq!(storage.object_count(), 0);
+}
+
+#[tokio::test]
+async fn lost_connection_is_transient() {
+    let (remote, server) = connect(InMemoryStorage::new()).await;
+    server.abort();
+    let _ = server.await;
+
+    let error = remote.list().await.unwrap_err();
+    assert!(is_transient(&error), "{:?}", error);
+}
@@ -0,0 +1,459 @@
+//! Backs up directories and streams into `InMemoryStorage` and restores them.
+
+use std::{
+    fs,
+    io::{self, Cursor},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use async_trait::async_trait;
+use lepatch::{
+    command::{BackupOptions, RestoreOptions, backup, backup_list, backup_stream, restore},
+    crypto::{ChunkIdKey, EncryptionConfig, MasterKey, RepositoryKey},
+    location::Location,
+    progress::{self, ProgressEvent},
+    reader::StreamReadSeeker,
+    storage::{DryRunStorage, EncryptedStorage, InMemoryStorage, ObjectKind, StorageGet},
+};
+
+/// Bytes that do not repeat within a chunk, so every chunk is distinct.
+fn content(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn write(root: &Path, path: &str, buffer: &[u8]) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, buffer).unwrap();
+}
+
+fn fill(root: &Path) {
+    write(root, "empty", b"");
+    write(root, "small.txt", b"hello");
+    write(root, "large.bin", &content(1024 * 1024, 1));
+    write(root, "nested/deeper/file", &content(100_000, 2));
+}
+
+fn assert_same(expected: &Path, actual: &Path, paths: &[&str]) {
+    for path in paths {
+        assert_eq!(
+            fs::read(expected.join(path)).unwrap(),
+            fs::read(actual.join(path)).unwrap(),
+            "{} differs",
+            path
+        );
+    }
+}
+
+#[tokio::test]
+async fn backup_and_restore_directory() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let storage = InMemoryStorage::new();
+    let key = backup(source.path(), storage.clone(), BackupOptions::default())
+        .await
+        .unwrap();
+
+    restore(
+        destination.path(),
+        key,
+        storage.clone(),
+        RestoreOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_same(
+        source.path(),
+        destination.path(),
+        &["empty", "small.txt", "large.bin", "nested/deeper/file"],
+    );
+}
+
+#[tokio::test]
+async fn second_backup_reuses_unchanged_chunks() {
+    let source = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let storage = InMemoryStorage::new();
+    let base_key = backup(source.path(), storage.clone(), BackupOptions::default())
+        .await
+        .unwrap();
+    let objects = storage.object_count();
+
+    write(source.path(), "added", &content(50_000, 3));
+    let options = BackupOptions {
+        base_key: Some(base_key),
+        ..Default::default()
+    };
+    let key = backup(source.path(), storage.clone(), options)
+        .await
+        .unwrap();
+
+    // The new file spans a few chunks; everything else is reused.
+    assert!(storage.object_count() - objects <= 1 + 50_000 / (8 * 1024));
+
+    let destination = tempfile::tempdir().unwrap();
+    restore(destination.path(), key, storage, RestoreOptions::default())
+        .await
+        .unwrap();
+    assert_same(
+        source.path(),
+        destination.path(),
+        &["large.bin", "nested/deeper/file", "added"],
+    );
+}
+
+#[tokio::test]
+async fn chunk_map_reuses_chunks_without_base_snapshot() {
+    let source = tempfile::tempdir().unwrap();
+    let state = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let storage = InMemoryStorage::new();
+    let options = BackupOptions {
+        chunk_map_file: Some(state.path().join("repo.chunks")),
+        ..Default::default()
+    };
+    backup(source.path(), storage.clone(), options.clone())
+        .await
+        .unwrap();
+    let objects = storage.object_count();
+
+    write(source.path(), "added", &content(50_000, 3));
+    let key = backup(source.path(), storage.clone(), options)
+        .await
+        .unwrap();
+
+    assert!(storage.object_count() - objects <= 1 + 50_000 / (8 * 1024));
+
+    let destination = tempfile::tempdir().unwrap();
+    restore(destination.path(), key, storage, RestoreOptions::default())
+        .await
+        .unwrap();
+    assert_same(
+        source.path(),
+        destination.path(),
+        &["large.bin", "nested/deeper/file", "added"],
+    );
+}
+
+#[tokio::test]
+async fn dry_run_reads_chunk_map_without_saving_it() {
+    let source = tempfile::tempdir().unwrap();
+    let state = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let map_path = state.path().join("repo.chunks");
+    let storage = InMemoryStorage::new();
+    let options = BackupOptions {
+        chunk_map_file: Some(map_path.clone()),
+        ..Default::default()
+    };
+    backup(source.path(), storage.clone(), options.clone())
+        .await
+        .unwrap();
+    let map = fs::read(&map_path).unwrap();
+
+    write(source.path(), "added", &content(50_000, 3));
+    let (sender, mut receiver) = progress::channel();
+    let options = BackupOptions {
+        progress: Some(sender),
+        chunk_map_read_only: true,
+        ..options
+    };
+    backup(source.path(), DryRunStorage::new(storage), options)
+        .await
+        .unwrap();
+
+    let mut reused = 0;
+    while let Ok(event) = receiver.try_recv() {
+        if let ProgressEvent::ChunkProcessed { reused: true, .. } = event {
+            reused += 1;
+        }
+    }
+    assert!(reused > 0);
+    assert_eq!(fs::read(&map_path).unwrap(), map);
+}
+
+#[tokio::test]
+async fn unreadable_chunk_map_is_reported() {
+    let source = tempfile::tempdir().unwrap();
+    let state = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let map_path = state.path().join("repo.chunks");
+    fs::write(&map_path, b"garbage").unwrap();
+
+    let (sender, mut receiver) = progress::channel();
+    let options = BackupOptions {
+        progress: Some(sender),
+        chunk_map_file: Some(map_path.clone()),
+        ..Default::default()
+    };
+    backup(source.path(), InMemoryStorage::new(), options)
+        .await
+        .unwrap();
+
+    let mut dropped = Vec::new();
+    while let Ok(event) = receiver.try_recv() {
+        if let ProgressEvent::ChunkMapDropped { path, .. } = event {
+            dropped.push(path);
+        }
+    }
+    assert_eq!(dropped, std::slice::from_ref(&map_path));
+    assert_ne!(fs::read(&map_path).unwrap(), b"garbage");
+}
+
+#[tokio::test]
+async fn exported_config_backs_up_but_cannot_restore() {
+    let source = tempfile::tempdir().unwrap();
+    let state = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let master_key = MasterKey::generate();
+    let config = EncryptionConfig::new(&master_key, b"password", None, true).unwrap();
+    let exported = config.export(&master_key).unwrap();
+    assert!(exported.slots.is_empty());
+
+    let key = exported.repository_key(None).unwrap();
+    assert!(!key.can_open());
+
+    let storage = InMemoryStorage::new();
+    let options = BackupOptions {
+        hash_key: Some(key.chunk_id_key()),
+        chunk_map_file: Some(state.path().join("repo.chunks")),
+        ..Default::default()
+    };
+    let encrypted = Arc::new(EncryptedStorage::new(storage.clone(), key.clone()));
+    let snapshot = backup(source.path(), encrypted.clone(), options)
+        .await
+        .unwrap();
+
+    let options = RestoreOptions {
+        hash_key: Some(key.chunk_id_key()),
+        ..Default::default()
+    };
+    let e = restore(destination.path(), snapshot.clone(), encrypted, options)
+        .await
+        .unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+
+    let key = config.repository_key(Some(master_key)).unwrap();
+    let options = RestoreOptions {
+        hash_key: Some(key.chunk_id_key()),
+        ..Default::default()
+    };
+    let encrypted = EncryptedStorage::new(storage, key);
+    restore(destination.path(), snapshot, encrypted, options)
+        .await
+        .unwrap();
+    assert_same(
+        source.path(),
+        destination.path(),
+        &["empty", "small.txt", "large.bin", "nested/deeper/file"],
+    );
+}
+
+#[tokio::test]
+async fn backup_and_restore_list() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let storage = InMemoryStorage::new();
+    let paths = vec!["small.txt".into(), "./nested/deeper/file".into()];
+    let key = backup_list(
+        source.path(),
+        paths,
+        storage.clone(),
+        BackupOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    restore(destination.path(), key, storage, RestoreOptions::default())
+        .await
+        .unwrap();
+
+    assert_same(
+        source.path(),
+        destination.path(),
+        &["small.txt", "nested/deeper/file"],
+    );
+    assert!(!destination.path().join("large.bin").exists());
+}
+
+#[tokio::test]
+async fn backup_list_refuses_paths_outside_root() {
+    let source = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let paths = vec!["nested/../../escaped".into()];
+    let result = backup_list(
+        source.path(),
+        paths,
+        InMemoryStorage::new(),
+        BackupOptions::default(),
+    )
+    .await;
+
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn backup_and_restore_stream() {
+    let destination = tempfile::tempdir().unwrap();
+    let buffer = content(300_000, 4);
+
+    let storage = InMemoryStorage::new();
+    let key = backup_stream(
+        "dump.sql".into(),
+        Cursor::new(buffer.clone()),
+        storage.clone(),
+        BackupOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    restore(destination.path(), key, storage, RestoreOptions::default())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        fs::read(destination.path().join("dump.sql")).unwrap(),
+        buffer
+    );
+}
+
+#[tokio::test]
+async fn backup_and_restore_encrypted() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let key = RepositoryKey::Master(MasterKey::generate());
+    let hash_key = Some(key.chunk_id_key());
+    let storage = InMemoryStorage::new();
+
+    let options = BackupOptions {
+        hash_key: hash_key.clone(),
+        ..Default::default()
+    };
+    let encrypted = EncryptedStorage::new(storage.clone(), key.clone());
+    let snapshot = backup(source.path(), encrypted, options).await.unwrap();
+
+    let options = RestoreOptions {
+        hash_key,
+        ..Default::default()
+    };
+    let encrypted = EncryptedStorage::new(storage, key);
+    restore(destination.path(), snapshot, encrypted, options)
+        .await
+        .unwrap();
+
+    assert_same(
+        source.path(),
+        destination.path(),
+        &["empty", "small.txt", "large.bin", "nested/deeper/file"],
+    );
+}
+
+#[tokio::test]
+async fn restore_rejects_chunks_not_matching_their_id() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let storage = InMemoryStorage::new();
+    let key = backup(source.path(), storage.clone(), BackupOptions::default())
+        .await
+        .unwrap();
+
+    let options = RestoreOptions {
+        hash_key: Some(ChunkIdKey::from_bytes([7; 32])),
+        ..Default::default()
+    };
+    let result = restore(destination.path(), key, storage, options).await;
+
+    assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
+
+/// Counts the chunks read whole and those read in part.
+#[derive(Debug, Default)]
+struct CountingStorage {
+    inner: InMemoryStorage,
+    whole: AtomicUsize,
+    ranges: AtomicUsize,
+}
+
+#[async_trait]
+impl StorageGet for CountingStorage {
+    async fn get(&self, key: &Location, kind: ObjectKind) -> io::Result<StreamReadSeeker> {
+        self.inner.get(key, kind).await
+    }
+
+    async fn get_range(
+        &self,
+        key: &Location,
+        offset: u64,
+        len: u64,
+        kind: ObjectKind,
+    ) -> io::Result<StreamReadSeeker> {
+        self.ranges.fetch_add(1, Ordering::Relaxed);
+        self.inner.get_range(key, offset, len, kind).await
+    }
+
+    async fn get_batch(&self, keys: &[Location], kind: ObjectKind) -> io::Result<Vec<Vec<u8>>> {
+        self.whole.fetch_add(keys.len(), Ordering::Relaxed);
+        self.inner.get_batch(keys, kind).await
+    }
+}
+
+#[tokio::test]
+async fn restore_reads_only_the_part_of_shared_chunks() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    fill(source.path());
+
+    let storage = Arc::new(CountingStorage::default());
+    let key = backup(
+        source.path(),
+        storage.inner.clone(),
+        BackupOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    restore(
+        destination.path(),
+        key,
+        storage.clone(),
+        RestoreOptions::default(),
+    )
+    .await
+    .unwrap();
+
+    assert_same(
+        source.path(),
+        destination.path(),
+        &["empty", "small.txt", "large.bin", "nested/deeper/file"],
+    );
+    assert!(storage.whole.load(Ordering::Relaxed) > 0);
+    assert!(storage.ranges.load(Ordering::Relaxed) > 0);
+}
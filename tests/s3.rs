@@ -0,0 +1,137 @@
+//! Round trips through a real S3 compatible store. The tests are ignored by
+//! default; point them at a scratch bucket, e.g. a local MinIO:
+//!
+//! ```sh
+//! AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin \
+//! LEPATCH_S3_ENDPOINT=http://127.0.0.1:9000 LEPATCH_S3_BUCKET=lepatch \
+//!     cargo test --features s3 --test s3 -- --ignored
+//! ```
+#![cfg(feature = "s3")]
+
+use std::{
+    env,
+    io::{self, Cursor},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use lepatch::storage::{
+    ObjectKind, S3Config, S3Storage, StorageDelete, StorageGet, StorageList, StoragePut,
+};
+use tokio::io::AsyncReadExt;
+
+/// Opens the bucket named by the environment under a prefix of its own, so
+/// runs never see each other's objects.
+async fn open(multipart_threshold: u64) -> S3Storage {
+    let bucket = env::var("LEPATCH_S3_BUCKET").expect("LEPATCH_S3_BUCKET is set");
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+
+    let mut config = S3Config::new(bucket, format!("lepatch-test/{:x}/", nanos));
+    config.endpoint = env::var("LEPATCH_S3_ENDPOINT").ok();
+    config.region = Some(env::var("LEPATCH_S3_REGION").unwrap_or_else(|_| "us-east-1".into()));
+    config.force_path_style = true;
+    config.multipart_threshold = multipart_threshold;
+    config.part_size = 5 * 1024 * 1024;
+
+    S3Storage::new(config).await.unwrap()
+}
+
+fn object(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|v| (v % 251) as u8 ^ seed).collect()
+}
+
+async fn put(storage: &S3Storage, buffer: &[u8]) -> io::Result<lepatch::location::Location> {
+    let reader = Box::new(Cursor::new(buffer.to_vec()));
+    storage
+        .put(reader, buffer.len() as u64, ObjectKind::Chunk)
+        .await
+}
+
+#[tokio::test]
+#[ignore = "needs an S3 compatible endpoint"]
+async fn put_get_list_delete() {
+    let storage = open(16 * 1024 * 1024).await;
+    let buffer = object(100_000, 1);
+
+    let key = put(&storage, &buffer).await.unwrap();
+    assert_eq!(key, put(&storage, &buffer).await.unwrap());
+
+    let mut read = Vec::new();
+    storage
+        .get(&key, ObjectKind::Chunk)
+        .await
+        .unwrap()
+        .read_to_end(&mut read)
+        .await
+        .unwrap();
+    assert_eq!(read, buffer);
+
+    let mut range = Vec::new();
+    storage
+        .get_range(&key, 1000, 5000, ObjectKind::Chunk)
+        .await
+        .unwrap()
+        .read_to_end(&mut range)
+        .await
+        .unwrap();
+    assert_eq!(range, buffer[1000..6000]);
+    assert!(
+        storage
+            .get_range(&key, 99_000, 5000, ObjectKind::Chunk)
+            .await
+            .is_err()
+    );
+
+    assert_eq!(storage.list().await.unwrap(), vec![key.clone()]);
+
+    storage.delete(&key).await.unwrap();
+    assert!(storage.list().await.unwrap().is_empty());
+    assert!(storage.get(&key, ObjectKind::Chunk).await.is_err());
+}
+
+#[tokio::test]
+#[ignore = "needs an S3 compatible endpoint"]
+async fn put_and_get_batch() {
+    let storage = open(16 * 1024 * 1024).await;
+    let objects: Vec<Bytes> = (0..40)
+        .map(|v| object(10_000 + v, v as u8).into())
+        .collect();
+
+    let keys = storage
+        .put_batch(&objects, ObjectKind::Chunk)
+        .await
+        .unwrap();
+    assert_eq!(keys.len(), objects.len());
+
+    let read = storage.get_batch(&keys, ObjectKind::Chunk).await.unwrap();
+    assert!(read.iter().zip(objects.iter()).all(|(a, b)| a[..] == b[..]));
+
+    for key in keys.iter() {
+        storage.delete(key).await.unwrap();
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs an S3 compatible endpoint"]
+async fn multipart_upload() {
+    let storage = open(6 * 1024 * 1024).await;
+    let buffer = object(12 * 1024 * 1024 + 17, 2);
+
+    let key = put(&storage, &buffer).await.unwrap();
+
+    let mut read = Vec::new();
+    storage
+        .get(&key, ObjectKind::Chunk)
+        .await
+        .unwrap()
+        .read_to_end(&mut read)
+        .await
+        .unwrap();
+    assert_eq!(read.len(), buffer.len());
+    assert!(read == buffer);
+
+    storage.delete(&key).await.unwrap();
+}